@@ -1,7 +1,7 @@
 mov v0, 255 ; Set R to 255
 mov v1, 0 ; Set G to 0
 mov v2, 0 ; Set B to 0
-call fill_screen
+sys fill_screen
 mov v0, 255 ; Set R to 255
 mov v1, 0 ; Set G to 0
 mov v2, 255 ; Set B to 255
@@ -10,7 +10,7 @@ mov v4, 73 ; Set starting x to 73
 mov v5, 300 ; Set starting y to 300
 mov v6, 230 ; Set ending x to 230
 mov v7, 67 ; Set ending y to 67
-call draw_line
+sys draw_line
 mov v0, 255 ; Set R to 255
 mov v1, 255 ; Set G to 0
 mov v2, 255 ; Set B to 255
@@ -19,4 +19,5 @@ mov v4, 42 ; Set starting x to 73
 mov v5, 153 ; Set starting y to 300
 mov v6, 80 ; Set ending x to 230
 mov v7, 23 ; Set ending y to 67
-call draw_line
+sys draw_line
+hlt
@@ -1,6 +1,6 @@
-mov 0, v4
-mov 0, v5
-call load_bmp
+mov v4, 0
+mov v5, 0
+sys load_bmp
 
 mov v0, 255
 mov v1, 0
@@ -10,4 +10,5 @@ mov v4, 100
 mov v5, 100
 
 mov v6, 80
-call fill_rect
+sys fill_rect
+hlt
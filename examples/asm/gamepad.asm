@@ -0,0 +1,56 @@
+; Moves a square with the gamepad's D-pad, once per frame, and turns it red
+; while A is held. Run it with --gamepad keyboard to use the arrow keys and
+; Z, or with --gamepad-script examples/asm/gamepad.pad to play a recording.
+; The vertical blank is polled through the interrupt controller, so the
+; square moves at the same speed whatever the loop costs.
+mov v4, 192             ; x
+mov v5, 144             ; y
+mov v6, 16
+
+frame:
+    in r0, 0x20         ; wait for the next vertical blank
+    and r0, 4
+    je frame
+    out 0x20, 4         ; acknowledge it
+
+    in r0, 0x70         ; status
+    and r0, 1           ; bit 0: a gamepad is connected
+    je frame
+
+    in r1, 0x71         ; buttons
+    mov r2, r1
+    and r2, 0x0100      ; up
+    je not_up
+    sub v5, 2
+not_up:
+    mov r2, r1
+    and r2, 0x0200      ; down
+    je not_down
+    add v5, 2
+not_down:
+    mov r2, r1
+    and r2, 0x0400      ; left
+    je not_left
+    sub v4, 2
+not_left:
+    mov r2, r1
+    and r2, 0x0800      ; right
+    je not_right
+    add v4, 2
+not_right:
+
+    mov v0, 255         ; white, or red while A is held
+    mov v1, 255
+    mov v2, 255
+    and r1, 0x0001      ; A
+    je draw
+    mov v1, 0
+    mov v2, 0
+draw:
+    sys clear_screen
+    sys fill_rect
+
+    in r1, 0x71
+    and r1, 0x0080      ; start ends the program
+    je frame
+    hlt
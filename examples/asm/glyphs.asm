@@ -6,8 +6,9 @@ mov v0, 255
 mov v1, 255
 mov v2, 255
 
-call draw_character
+sys draw_character
 inc v3
 add v4, 30
 cmp v4, 280
 jne 18
+hlt
@@ -6,28 +6,33 @@ mov v1, 255        ; color G
 mov v2, 255        ; color B
 
 mov r2, 10         ; kerning (character spacing)
-mov r4, 32         ; space keycode (ASCII)
-mov r5, 8          ; backspace keycode (ASCII)
+mov r4, 32         ; space
+mov r5, 8          ; backspace
 mov r6, 0          ; underscore_drawn = 0
 
 ; === Main Loop ===
+; Characters come from the text input queue, with shift and the keyboard
+; layout already applied, and nothing typed while one is being drawn is lost.
 loop:
-    cmp k0, 0
+    in r0, 0x65         ; text input status
+    and r0, 1           ; bit 0: a character is waiting
     je no_key
 
+    in r0, 0x64         ; take the oldest character
     mov r6, 0           ; reset underscore_drawn
 
-    cmp k0, r5
+    cmp r0, r5
     je handle_backspace
 
-    cmp k0, r4
+    cmp r0, r4
     je handle_space     ; skip rendering, but still move forward
+    jb loop             ; other control characters, such as Enter
 
-    mov v3, k0
-    call draw_character
+    mov v3, r0
+    sys draw_character
 
     add v4, r2
-    jmp wait_release
+    jmp loop
 
 ; === Handle No Key Press ===
 no_key:
@@ -35,24 +40,18 @@ no_key:
     je loop
 
     mov v3, 95
-    call draw_character
+    sys draw_character
     mov r6, 1
     jmp loop
 
 ; === Handle Backspace ===
 handle_backspace:
     sub v4, r2
-    call clear_screen
-    jmp wait_release
+    sys clear_screen
+    jmp loop
 
 ; === Handle Space ===
 handle_space:
     add v4, r2
     mov r6, 1
-    jmp wait_release
-
-; === Wait for Key Release ===
-wait_release:
-    cmp k0, 0
-    jne wait_release
     jmp loop
@@ -0,0 +1,42 @@
+; Moves a square from a timer interrupt while the main program only idles.
+; The handler's address goes into the interrupt vector table at the start of
+; the kernel region: vector N is a code offset and a code segment at byte 4N.
+; The timer raises IRQ 0, which is delivered through vector 0x08.
+mov r0, ds
+mov ds, 0
+store 0x20, on_timer    ; vector 0x08: offset
+store 0x22, cs          ; and segment
+store 0xC0, set_color   ; vector 0x30: a software trap
+store 0xC2, cs
+mov ds, r0
+
+out 0x41, 20000         ; one tick every 20000 cycles
+out 0x42, 0
+out 0x40, 1
+out 0x21, 1             ; only let the timer interrupt through
+
+mov v4, 8
+mov v5, 144
+mov v6, 16
+int 0x30
+sti
+
+idle:
+jmp idle
+
+on_timer:
+    out 0x43, 0         ; acknowledge the timer
+    sys clear_screen
+    sys fill_rect
+    add v4, 4
+    cmp v4, 368
+    jb done
+    hlt
+done:
+    iret
+
+; Picks the square's color
+set_color:
+    mov v0, 255
+    mov v1, 160
+    iret
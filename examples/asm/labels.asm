@@ -1,14 +1,18 @@
-test:
-    mov r1, 'A'
-
-jmp test
-
-mov v3, r1
 mov v4, 100
 mov v5, 100
 mov v0, 255
 mov v1, 255
 mov v2, 255
 
-call draw_character
+mov r1, 'A'
+call print_character
+mov r1, 'B'
+call print_character
 hlt
+
+; Draws the character in r1 and moves the cursor right
+print_character:
+    mov v3, r1
+    sys draw_character
+    add v4, 16
+    ret
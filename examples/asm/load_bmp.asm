@@ -1,8 +1,9 @@
-mov 255, v0
-mov 255, v1
-mov 255, v2
-call fill_screen
+mov v0, 255
+mov v1, 255
+mov v2, 255
+sys fill_screen
 
-mov 0, v4 ; X position
-mov 0, v5 ; Y position
-call load_bmp
+mov v4, 0 ; X position
+mov v5, 0 ; Y position
+sys load_bmp
+hlt
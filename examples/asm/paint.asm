@@ -0,0 +1,54 @@
+; Paints with the mouse: hold the left button to draw, click the right one to
+; clear the screen and scroll the wheel to change the brush size.
+; Everything happens in the mouse interrupt handler, IRQ 3 on vector 0x0B.
+mov r0, ds
+mov ds, 0
+store 0x2C, on_mouse    ; vector 0x0B: offset
+store 0x2E, cs          ; and segment
+mov ds, r0
+
+out 0x21, 8             ; only let the mouse interrupt through
+out 0x6D, 1             ; ask the mouse for interrupts
+
+mov v0, 255             ; brush color
+mov v1, 200
+mov v2, 0
+mov v6, 4               ; brush size
+sti
+
+idle:
+jmp idle
+
+on_mouse:
+    in r0, 0x6C         ; status: what changed since the last read
+    in v4, 0x68         ; x
+    in v5, 0x69         ; y
+
+    in r1, 0x6B         ; wheel lines, signed
+    add v6, r1
+    cmp v6, 1
+    jl too_small
+    cmp v6, 32
+    jg too_big
+
+buttons:
+    in r1, 0x6A
+    mov r2, r1
+    and r2, 2           ; right button
+    je paint
+    sys clear_screen
+paint:
+    and r1, 1           ; left button
+    je done
+    and r0, 8           ; status bit 3: the cursor is over the screen
+    je done
+    sys fill_rect
+done:
+    iret
+
+too_small:
+    mov v6, 1
+    jmp buttons
+too_big:
+    mov v6, 32
+    jmp buttons
@@ -14,7 +14,7 @@ mov v13, 320
 mov v14, 64
 mov v15, 256
 
-call fill_quad
+sys fill_quad
 
 mov v0, 0
 mov v1, 255
@@ -32,7 +32,7 @@ mov v13, 25
 mov v14, 50
 mov v15, 25
 
-call fill_quad
+sys fill_quad
 
 mov v0, 0
 mov v1, 0
@@ -50,4 +50,5 @@ mov v13, 25
 mov v14, 50
 mov v15, 10
 
-call fill_quad
+sys fill_quad
+hlt
@@ -1 +1,29 @@
+; Stores a value in the heap, far outside the first 64K words, and reads it
+; back through the data segment register.
+; Segments are 16-word paragraphs: 0x0080 is the code region (the default),
+; 0x1080 the data region and 0x1C80 the start of the heap.
+mov r0, ds          ; remember the default data segment
 
+mov ds, 0x1C80
+mov r1, 1234
+store 0, r1
+mov ds, r0
+
+load r2, 0          ; reads the program's first word, not the heap
+cmp r2, 1234
+je fail
+
+mov ds, 0x1C80
+load r2, 0
+mov ds, r0
+cmp r2, 1234
+jne fail
+
+mov v1, 255         ; green: the heap round trip worked
+sys fill_screen
+hlt
+
+fail:
+mov v0, 255         ; red
+sys fill_screen
+hlt
@@ -1,8 +1,5 @@
-string:
-    ds "Hello World!", 0
-
 mov r1, string
-load v3, r1
+load8 v3, r1
 
 mov v4, 10
 mov v5, 20
@@ -12,18 +9,20 @@ mov v2, 255
 
 mov r2, 8 ; Kerning
 
-call draw_character
-
 loop:
     cmp v3, 0
     je end
 
+    sys draw_character
+
     inc r1
 
     load8 v3, r1
     add v4, r2
-    cmp v3, 0
-    jne loop
+    jmp loop
 
 end:
     hlt
+
+string:
+    ds "Hello World!", 0
@@ -0,0 +1,29 @@
+; Moves a square across the screen once per timer tick instead of counting
+; loop iterations, so it runs at the same pace whatever the loop costs.
+; The timer counts CPU cycles: with --clock 1MHz a 20000 cycle period is 50
+; ticks per second.
+out 0x41, 20000     ; period, low word
+out 0x42, 0         ; period, high word
+out 0x40, 1         ; enable
+
+mov v1, 255         ; green
+mov v4, 8
+mov v5, 144
+mov v6, 16
+
+wait:
+in r0, 0x43         ; status: bit 0 is set when a period has ended
+cmp r0, 0
+je wait
+out 0x43, 0         ; acknowledge
+
+sys clear_screen
+sys fill_rect
+add v4, 4
+
+in r1, 0x44         ; ticks so far
+cmp r1, 90
+jb wait
+
+out 0x40, 0         ; stop the timer
+hlt
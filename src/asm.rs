@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::cpu::{FunctionCall, InvalidOpcodeString, MicroCVMCpu, OpcodeType, Register};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    InvalidOpcode(String),
+    InvalidOperand(String),
+    OperandCount {
+        mnemonic: String,
+        expected: usize,
+        found: usize,
+    },
+    DuplicateLabel(String),
+    // The label's offset does not fit in a 16-bit operand.
+    LabelOutOfRange(String),
+    UnterminatedString,
+    // Immediates in the register ID ranges would be decoded as registers.
+    AmbiguousImmediate(u16),
+}
+
+impl Display for AsmErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmErrorKind::InvalidOpcode(mnemonic) => write!(f, "Invalid Opcode: {}", mnemonic),
+            AsmErrorKind::InvalidOperand(operand) => write!(f, "Invalid operand: {}", operand),
+            AsmErrorKind::OperandCount {
+                mnemonic,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} operand(s) but {} were given",
+                mnemonic, expected, found
+            ),
+            AsmErrorKind::DuplicateLabel(label) => write!(f, "Label `{}` is defined twice", label),
+            AsmErrorKind::LabelOutOfRange(label) => {
+                write!(f, "Label `{}` is beyond the 16-bit address range", label)
+            }
+            AsmErrorKind::UnterminatedString => write!(f, "Unterminated string"),
+            AsmErrorKind::AmbiguousImmediate(value) => write!(
+                f,
+                "Immediate {:#06X} has the same encoding as a register",
+                value
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl AsmError {
+    pub fn new(line: usize, kind: AsmErrorKind) -> Self {
+        Self { line, kind }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+impl From<InvalidOpcodeString> for AsmErrorKind {
+    fn from(error: InvalidOpcodeString) -> Self {
+        AsmErrorKind::InvalidOpcode(error.0)
+    }
+}
+
+enum Statement {
+    Instruction {
+        opcode_type: OpcodeType,
+        operands: Vec<String>,
+    },
+    Data(Vec<u8>),
+}
+
+struct Line {
+    number: usize,
+    statement: Statement,
+}
+
+impl Statement {
+    // Size in 16-bit words. Data is padded to a whole word so that the next
+    // instruction stays aligned.
+    fn word_count(&self) -> usize {
+        match self {
+            Statement::Instruction { operands, .. } => 1 + operands.len(),
+            Statement::Data(bytes) => bytes.len().div_ceil(2),
+        }
+    }
+}
+
+/// Assembles MicroCVM source into a little-endian binary that
+/// `MicroCVMCpu::load_program` can run.
+///
+/// Labels in front of `ds` data resolve to byte offsets for `load`, `store`,
+/// `load8` and `store8`; every other label resolves to the word offset of the
+/// instruction it names, for `jmp`, `call` and the conditional jumps.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut pending_labels = Vec::new();
+    let mut address: usize = 0;
+
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let error = |kind| AsmError::new(number, kind);
+        let mut text = strip_comment(text).trim();
+
+        while let Some((label, rest)) = split_label(text) {
+            pending_labels.push((label.to_string(), number));
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let statement = parse_statement(text).map_err(error)?;
+        let is_data = matches!(statement, Statement::Data(_));
+
+        for (label, label_line) in pending_labels.drain(..) {
+            let offset = if is_data {
+                address.checked_mul(2)
+            } else {
+                Some(address)
+            };
+            define_label(&mut labels, label, label_line, offset)?;
+        }
+
+        address += statement.word_count();
+        lines.push(Line { number, statement });
+    }
+
+    // Labels after the last statement point just past the end of the program.
+    for (label, label_line) in pending_labels {
+        define_label(&mut labels, label, label_line, Some(address))?;
+    }
+
+    let mut words = Vec::with_capacity(address);
+    for line in &lines {
+        let error = |kind| AsmError::new(line.number, kind);
+
+        match &line.statement {
+            Statement::Instruction {
+                opcode_type,
+                operands,
+            } => {
+                words.push(*opcode_type as u16);
+                for operand in operands {
+                    words.push(resolve_operand(operand, *opcode_type, &labels).map_err(error)?);
+                }
+            }
+            Statement::Data(bytes) => {
+                words.extend(bytes.chunks(2).map(|chunk| {
+                    u16::from_le_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)])
+                }));
+            }
+        }
+    }
+
+    Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+}
+
+fn define_label(
+    labels: &mut HashMap<String, u16>,
+    label: String,
+    line: usize,
+    offset: Option<usize>,
+) -> Result<(), AsmError> {
+    let Some(offset) = offset.and_then(|offset| u16::try_from(offset).ok()) else {
+        return Err(AsmError::new(line, AsmErrorKind::LabelOutOfRange(label)));
+    };
+
+    if labels.insert(label.clone(), offset).is_some() {
+        return Err(AsmError::new(line, AsmErrorKind::DuplicateLabel(label)));
+    }
+
+    Ok(())
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut literals = Literals::default();
+
+    for (index, character) in text.char_indices() {
+        if !literals.scan(character) && character == ';' {
+            return &text[..index];
+        }
+    }
+
+    text
+}
+
+// Tracks the string and character literals in a line, so the separators in
+// them are not taken for comments or operand separators.
+#[derive(Default)]
+struct Literals {
+    in_string: bool,
+    in_character: bool,
+    escaped: bool,
+}
+
+impl Literals {
+    // Returns whether `character` is part of a literal, quotes included.
+    fn scan(&mut self, character: char) -> bool {
+        let inside = self.in_string || self.in_character;
+
+        if self.escaped {
+            self.escaped = false;
+        } else if character == '\\' && inside {
+            self.escaped = true;
+        } else if character == '"' && !self.in_character {
+            self.in_string = !self.in_string;
+            return true;
+        } else if character == '\'' && !self.in_string {
+            self.in_character = !self.in_character;
+            return true;
+        }
+
+        inside
+    }
+
+    fn unterminated(&self) -> bool {
+        self.in_string || self.in_character
+    }
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    let mut characters = label.chars();
+    let starts_like_identifier = characters
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+
+    if starts_like_identifier && characters.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Some((label, rest))
+    } else {
+        None
+    }
+}
+
+fn parse_statement(text: &str) -> Result<Statement, AsmErrorKind> {
+    let (mnemonic, rest) = text
+        .split_once(char::is_whitespace)
+        .unwrap_or((text, ""));
+    let mnemonic = mnemonic.to_ascii_lowercase();
+    let operands = split_operands(rest)?;
+
+    if mnemonic == "ds" {
+        let mut bytes = Vec::new();
+        for operand in &operands {
+            parse_data(operand, &mut bytes)?;
+        }
+        return Ok(Statement::Data(bytes));
+    }
+
+    let opcode_type = OpcodeType::try_from(mnemonic.as_str())?;
+    let expected = MicroCVMCpu::get_opcode_argument_count(opcode_type) as usize;
+    if operands.len() != expected {
+        return Err(AsmErrorKind::OperandCount {
+            mnemonic,
+            expected,
+            found: operands.len(),
+        });
+    }
+
+    Ok(Statement::Instruction {
+        opcode_type,
+        operands,
+    })
+}
+
+fn split_operands(text: &str) -> Result<Vec<String>, AsmErrorKind> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut literals = Literals::default();
+
+    for character in text.chars() {
+        if !literals.scan(character) && character == ',' {
+            operands.push(current.trim().to_string());
+            current.clear();
+            continue;
+        }
+
+        current.push(character);
+    }
+
+    if literals.unterminated() {
+        return Err(AsmErrorKind::UnterminatedString);
+    }
+
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current.trim().to_string());
+    }
+
+    Ok(operands)
+}
+
+fn parse_data(operand: &str, bytes: &mut Vec<u8>) -> Result<(), AsmErrorKind> {
+    if let Some(string) = operand
+        .strip_prefix('"')
+        .and_then(|operand| operand.strip_suffix('"'))
+    {
+        bytes.extend(unescape(string).ok_or_else(|| invalid(operand))?.bytes());
+        return Ok(());
+    }
+
+    let value = parse_number(operand)
+        .filter(|value| *value <= 0xFF)
+        .ok_or_else(|| invalid(operand))?;
+    bytes.push(value as u8);
+    Ok(())
+}
+
+fn resolve_operand(
+    operand: &str,
+    opcode_type: OpcodeType,
+    labels: &HashMap<String, u16>,
+) -> Result<u16, AsmErrorKind> {
+    if let Ok(register) = Register::try_from(operand.to_ascii_lowercase().as_str()) {
+        return Ok(register as u16);
+    }
+
+    if opcode_type == OpcodeType::Sys
+        && let Ok(function) = FunctionCall::try_from(operand)
+    {
+        return Ok(function as u16);
+    }
+
+    let value = labels
+        .get(operand)
+        .copied()
+        .or_else(|| parse_number(operand))
+        .ok_or_else(|| invalid(operand))?;
+
+    if Register::try_from(value).is_ok() {
+        return Err(AsmErrorKind::AmbiguousImmediate(value));
+    }
+
+    Ok(value)
+}
+
+// Accepts decimal, 0x hex, 0b binary, a leading minus sign (two's
+// complement) and 'c' character literals.
+pub fn parse_number(text: &str) -> Option<u16> {
+    if let Some(character) = text
+        .strip_prefix('\'')
+        .and_then(|text| text.strip_suffix('\''))
+    {
+        let character = unescape(character)?;
+        let mut characters = character.chars();
+        let character = characters.next().filter(char::is_ascii)?;
+        return characters.next().is_none().then_some(character as u16);
+    }
+
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u16::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        u16::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse::<u16>().ok()?
+    };
+
+    if negative {
+        (value <= 0x8000).then(|| value.wrapping_neg())
+    } else {
+        Some(value)
+    }
+}
+
+// Resolves the escapes in string and character literals: \n, \r, \t, \0,
+// \\, \', \" and \u{hex}. This also reverses `str::escape_debug`, which input
+// recordings use for typed text.
+pub fn unescape(text: &str) -> Option<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut characters = text.chars();
+
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            result.push(character);
+            continue;
+        }
+
+        result.push(match characters.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            '"' => '"',
+            'u' => {
+                if characters.next()? != '{' {
+                    return None;
+                }
+
+                let mut digits = String::new();
+                loop {
+                    match characters.next()? {
+                        '}' => break,
+                        digit => digits.push(digit),
+                    }
+                }
+                char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?
+            }
+            _ => return None,
+        });
+    }
+
+    Some(result)
+}
+
+fn invalid(operand: &str) -> AsmErrorKind {
+    AsmErrorKind::InvalidOperand(operand.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<u16> {
+        assemble(source)
+            .unwrap()
+            .chunks(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn encodes_opcodes_registers_and_immediates() {
+        assert_eq!(
+            words("mov r0, 5\nadd v15, -1\nhlt\n"),
+            [
+                OpcodeType::Mov as u16,
+                Register::R0 as u16,
+                5,
+                OpcodeType::Add as u16,
+                Register::V15 as u16,
+                0xFFFF,
+                OpcodeType::Hlt as u16,
+            ]
+        );
+        assert_eq!(
+            words("sys fill_screen\nmov r1, 'A'\n"),
+            [
+                OpcodeType::Sys as u16,
+                FunctionCall::FillScreen as u16,
+                OpcodeType::Mov as u16,
+                Register::R1 as u16,
+                0x41,
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_code_labels_to_words_and_data_labels_to_bytes() {
+        let source = "start: jmp end\nmov r1, text\nend: hlt\ntext: ds \"hi\", 0\nafter:\n";
+        assert_eq!(
+            words(source),
+            [
+                OpcodeType::Jmp as u16,
+                5,
+                OpcodeType::Mov as u16,
+                Register::R1 as u16,
+                12,
+                OpcodeType::Hlt as u16,
+                u16::from_le_bytes(*b"hi"),
+                0,
+            ]
+        );
+
+        assert_eq!(words("jmp after\nafter:\n"), [OpcodeType::Jmp as u16, 2]);
+    }
+
+    #[test]
+    fn assembled_loops_run_to_completion() {
+        let mut cpu = MicroCVMCpu::from_source(
+            "mov r0, 0\nmov r1, 0\nloop: add r1, 3\ninc r0\ncmp r0, 5\njne loop\nhlt\n",
+        );
+        cpu.run_to_halt();
+
+        assert_eq!(cpu.registers[Register::index(Register::R0)], 5);
+        assert_eq!(cpu.registers[Register::index(Register::R1)], 15);
+    }
+
+    #[test]
+    fn rejects_immediates_that_encode_as_registers() {
+        assert_eq!(
+            assemble("nop\nmov r0, 0x1001\n"),
+            Err(AsmError::new(2, AsmErrorKind::AmbiguousImmediate(0x1001)))
+        );
+        assert_eq!(
+            assemble("mov r0, 0x4002"),
+            Err(AsmError::new(1, AsmErrorKind::AmbiguousImmediate(0x4002)))
+        );
+        assert!(assemble("mov r0, 0x1009").is_ok());
+    }
+
+    #[test]
+    fn reports_label_and_operand_errors() {
+        assert_eq!(
+            assemble("a: nop\na: hlt\n"),
+            Err(AsmError::new(2, AsmErrorKind::DuplicateLabel("a".to_string())))
+        );
+        assert_eq!(
+            assemble(&format!("ds \"{}\"\nfar: ds 0\n", "a".repeat(70_000))),
+            Err(AsmError::new(2, AsmErrorKind::LabelOutOfRange("far".to_string())))
+        );
+        assert_eq!(
+            assemble("jmp nowhere"),
+            Err(AsmError::new(1, AsmErrorKind::InvalidOperand("nowhere".to_string())))
+        );
+        assert_eq!(
+            assemble("mov r0"),
+            Err(AsmError::new(
+                1,
+                AsmErrorKind::OperandCount {
+                    mnemonic: "mov".to_string(),
+                    expected: 2,
+                    found: 1,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn separators_inside_literals_are_not_comments_or_operands() {
+        assert_eq!(strip_comment(r#"ds "a\";b", 0 ; comment"#), r#"ds "a\";b", 0 "#);
+        assert_eq!(strip_comment(r"mov r0, ';' ; c"), r"mov r0, ';' ");
+        assert_eq!(
+            split_operands(r#""a,\"b", ',', '\''"#).unwrap(),
+            [r#""a,\"b""#, "','", r"'\''"]
+        );
+        assert_eq!(split_operands(r#""open"#), Err(AsmErrorKind::UnterminatedString));
+        assert_eq!(words(r#"ds "\";", 0 ; comment"#), [u16::from_le_bytes(*b"\";"), 0]);
+    }
+
+    #[test]
+    fn unescape_reverses_escape_debug() {
+        let text = "a\u{1b}\u{200b}\"'\\\n\t\0\u{1F600}";
+        assert_eq!(unescape(&text.escape_debug().to_string()).as_deref(), Some(text));
+        assert_eq!(words(r"mov r0, '\u{41}'"), [OpcodeType::Mov as u16, Register::R0 as u16, 0x41]);
+
+        assert_eq!(unescape(r"\u{41"), None);
+        assert_eq!(unescape(r"\u41"), None);
+        assert_eq!(unescape(r"\u{110000}"), None);
+        assert_eq!(unescape(r"\q"), None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::asm;
+use crate::cpu;
+use crate::watch::{self, Watchpoint};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "MicroCVM virtual machine", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run a boot image (the default when no subcommand is given)
+    Run(Box<RunArgs>),
+
+    /// Assemble a source file into a binary the VM can load
+    Assemble {
+        /// Assembly source file
+        input: PathBuf,
+
+        /// Output binary; defaults to the input path with a `.bin` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print a boot image or raw binary as assembly
+    Disassemble {
+        /// ISO containing boot.bin, or a raw binary
+        input: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Boot image to run: an ISO containing boot.bin, or a raw binary
+    #[arg(default_value = "examples/images/test.iso")]
+    pub image: PathBuf,
+
+    /// Amount of guest RAM, e.g. 2M, 4096K or 2097152
+    #[arg(long, default_value = "2M", value_parser = parse_memory_size)]
+    pub memory: usize,
+
+    /// Framebuffer resolution in pixels, e.g. 384x288
+    #[arg(long, default_value = "384x288", value_parser = parse_resolution)]
+    pub resolution: (usize, usize),
+
+    /// Window pixels per framebuffer pixel
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..=16))]
+    pub scale: u32,
+
+    /// Run without a window and write the framebuffer to this PNG or PPM file
+    #[arg(long, value_name = "OUTPUT")]
+    pub headless: Option<PathBuf>,
+
+    /// Stop after executing this many instructions
+    #[arg(long, value_name = "COUNT")]
+    pub max_instructions: Option<u64>,
+
+    /// Throttle the windowed CPU to this clock frequency, e.g. 1MHz, 500kHz
+    /// or 2000000. Unthrottled by default
+    #[arg(long, value_name = "FREQUENCY", value_parser = parse_frequency)]
+    pub clock: Option<u64>,
+
+    /// Watch memory or video memory: [r:|w:|rw:]START[-END] with physical
+    /// byte addresses, or video:X,Y,WxH. May be given more than once
+    #[arg(long, value_name = "SPEC", value_parser = watch::parse_watchpoint)]
+    pub watch: Vec<Watchpoint>,
+
+    /// Stop execution on a watchpoint hit instead of only logging it
+    #[arg(long, requires = "watch")]
+    pub watch_pause: bool,
+
+    /// Start paused in an interactive debugger on the terminal
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Write an instruction trace to this file
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+
+    /// Only trace instructions whose pc is in this range, e.g. 0x10-0x40
+    #[arg(long, value_name = "START-END", requires = "trace", value_parser = parse_address_range)]
+    pub trace_range: Option<RangeInclusive<u16>>,
+
+    /// Start paused and wait for GDB on this address, e.g. 127.0.0.1:1234
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "debug")]
+    pub gdb: Option<String>,
+
+    /// Resume from a snapshot instead of booting the image
+    #[arg(long, value_name = "FILE")]
+    pub load_state: Option<PathBuf>,
+
+    /// Write a snapshot here when the guest stops. The F5 and F9 hotkeys
+    /// save to and load from this file, or `microcvm.state` if it is not set
+    #[arg(long, value_name = "FILE")]
+    pub save_state: Option<PathBuf>,
+
+    /// Connect a gamepad played from the keyboard
+    #[arg(long, value_enum, value_name = "BACKEND")]
+    pub gamepad: Option<GamepadBackend>,
+
+    /// Connect a gamepad that plays back a script of changes timed in CPU
+    /// cycles
+    #[arg(long, value_name = "FILE", conflicts_with = "gamepad")]
+    pub gamepad_script: Option<PathBuf>,
+
+    /// Record every input event delivered to the guest to this file, timed
+    /// in CPU cycles
+    #[arg(long, value_name = "FILE")]
+    pub record_input: Option<PathBuf>,
+
+    /// Play back input recorded with --record-input. Headless runs stop
+    /// where the recorded session ended
+    #[arg(long, value_name = "FILE", conflicts_with = "record_input")]
+    pub replay_input: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamepadBackend {
+    /// Arrow keys for the D-pad, Z X A S Q W for the buttons, Enter for Start
+    /// and right Shift for Select
+    Keyboard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Iso,
+    Raw,
+}
+
+impl ImageKind {
+    pub fn of(path: &Path) -> Self {
+        let is_iso = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("iso"));
+
+        if is_iso { ImageKind::Iso } else { ImageKind::Raw }
+    }
+}
+
+fn parse_memory_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1024),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    let size = digits
+        .parse::<usize>()
+        .map_err(|e| format!("invalid memory size `{}`: {}", value, e))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("memory size `{}` is too large", value))?;
+
+    if size < cpu::MIN_MEMORY_SIZE {
+        return Err(format!(
+            "memory size must be at least {} bytes",
+            cpu::MIN_MEMORY_SIZE
+        ));
+    }
+
+    if !size.is_multiple_of(2) {
+        return Err("memory size must be a whole number of 16-bit words".to_string());
+    }
+
+    Ok(size)
+}
+
+fn parse_frequency(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let digits = trimmed
+        .strip_suffix("Hz")
+        .or_else(|| trimmed.strip_suffix("hz"))
+        .unwrap_or(trimmed);
+
+    let (digits, multiplier) = match digits.chars().last() {
+        Some('K' | 'k') => (&digits[..digits.len() - 1], 1_000),
+        Some('M' | 'm') => (&digits[..digits.len() - 1], 1_000_000),
+        Some('G' | 'g') => (&digits[..digits.len() - 1], 1_000_000_000),
+        _ => (digits, 1),
+    };
+
+    let frequency = digits
+        .parse::<u64>()
+        .map_err(|e| format!("invalid frequency `{}`: {}", value, e))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("frequency `{}` is too large", value))?;
+
+    if frequency == 0 {
+        return Err("frequency must be non-zero".to_string());
+    }
+
+    Ok(frequency)
+}
+
+fn parse_resolution(value: &str) -> Result<(usize, usize), String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("invalid resolution `{}`, expected WIDTHxHEIGHT", value))?;
+
+    let width = width
+        .parse::<usize>()
+        .map_err(|e| format!("invalid width `{}`: {}", width, e))?;
+    let height = height
+        .parse::<usize>()
+        .map_err(|e| format!("invalid height `{}`: {}", height, e))?;
+
+    if width == 0 || height == 0 {
+        return Err("resolution must be non-zero".to_string());
+    }
+
+    if width
+        .checked_mul(height)
+        .is_none_or(|pixels| pixels > cpu::VIDEO_MEMORY)
+    {
+        return Err(format!(
+            "resolution {}x{} does not fit in {} pixels of video memory",
+            width,
+            height,
+            cpu::VIDEO_MEMORY
+        ));
+    }
+
+    Ok((width, height))
+}
+
+fn parse_address_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range `{}`, expected START-END", value))?;
+
+    let start = asm::parse_number(start.trim())
+        .ok_or_else(|| format!("invalid address `{}`", start))?;
+    let end = asm::parse_number(end.trim()).ok_or_else(|| format!("invalid address `{}`", end))?;
+
+    if start > end {
+        return Err(format!("range start {:#06X} is after its end {:#06X}", start, end));
+    }
+
+    Ok(start..=end)
+}
@@ -0,0 +1,40 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Sleeps shorter than this are not worth a syscall; the debt carries over.
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+// When the guest falls this far behind (the host was busy, or execution was
+// paused), the clock starts over instead of running flat out to catch up.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Paces the guest to a target clock frequency using its cycle counter.
+pub struct Clock {
+    frequency: u64,
+    start: Instant,
+    start_cycles: u64,
+}
+
+impl Clock {
+    pub fn new(frequency: u64, cycles: u64) -> Self {
+        Self {
+            frequency,
+            start: Instant::now(),
+            start_cycles: cycles,
+        }
+    }
+
+    /// Sleeps until the wall clock catches up with the guest at `cycles`.
+    pub fn throttle(&mut self, cycles: u64) {
+        let elapsed_cycles = cycles.saturating_sub(self.start_cycles) as u128;
+        let due = Duration::from_nanos((elapsed_cycles * 1_000_000_000 / self.frequency as u128) as u64);
+        let elapsed = self.start.elapsed();
+
+        if due > elapsed + MIN_SLEEP {
+            thread::sleep(due - elapsed);
+        } else if elapsed > due + MAX_LAG {
+            self.start = Instant::now();
+            self.start_cycles = cycles;
+        }
+    }
+}
@@ -3,9 +3,16 @@ use std::fmt::Display;
 use std::io;
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 
+use crate::devices::Devices;
+use crate::gamepad;
+use crate::history::Journal;
+use crate::input::{InputEvent, Recorder, Replay};
+use crate::interrupts;
 use crate::screen::DrawCommand;
 use crate::types::{Color, Point};
+use crate::watch::{Access, HitLocation, WatchHit, WatchTarget, Watchpoint};
 
 const MEM_TOTAL: usize = 2048 * 1024; //2MiB
 
@@ -20,14 +27,36 @@ const DATA_SIZE: usize = 0x10000;
 
 const STACK_OFFSET: usize = DATA_OFFSET + DATA_SIZE;
 const STACK_SIZE: usize = 0x8000;
+const STACK_BASE: usize = STACK_OFFSET / 2;
+const STACK_WORDS: u16 = (STACK_SIZE / 2) as u16;
 
 const HEAP_OFFSET: usize = STACK_OFFSET + STACK_SIZE;
+#[allow(dead_code)]
 const HEAP_SIZE: usize = MEM_TOTAL - HEAP_OFFSET;
 
-const VIDEO_MEMORY: usize = 1728 * 1024; //1.6875MiB
-const REGISTER_COUNT: usize = 25;
+pub const MIN_MEMORY_SIZE: usize = HEAP_OFFSET;
+
+pub const VIDEO_MEMORY: usize = 1728 * 1024; //1.6875MiB
+const REGISTER_COUNT: usize = 27;
+
+// Segment registers select a 16-word (32-byte) paragraph, so a 16-bit segment
+// and a 16-bit word offset together reach every word of the 2MiB memory.
+const PARAGRAPH_SHIFT: usize = 4;
+const CODE_SEGMENT: u16 = ((CODE_OFFSET / 2) >> PARAGRAPH_SHIFT) as u16;
 
 const FLAG_ZERO: u16 = 0x0001;
+const FLAG_CARRY: u16 = 0x0002;
+const FLAG_SIGN: u16 = 0x0004;
+const FLAG_OVERFLOW: u16 = 0x0008;
+const FLAG_INTERRUPT: u16 = 0x0010;
+
+// The interrupt vector table fills the start of the kernel region: vector N is
+// the code offset and code segment of its handler at bytes 4N..4N+3. An
+// all-zero entry means no handler is installed.
+const IVT_OFFSET: usize = KERNEL_OFFSET;
+const INTERRUPT_VECTORS: u16 = 256;
+// The cost of delivering a hardware interrupt, like `int`.
+const INTERRUPT_CYCLES: u64 = 8;
 
 #[derive(Default, Clone)]
 pub struct MicroCVMCpu {
@@ -37,12 +66,28 @@ pub struct MicroCVMCpu {
     pub pc: u16,
     pub sp: u16,
     pub flags: u16,
+    // Clock cycles spent by every instruction executed so far.
+    pub cycles: u64,
+    pub devices: Devices,
     pub framebuffer_width: usize,
     pub framebuffer_height: usize,
+    pub watchpoints: Vec<Watchpoint>,
+    // Filled by instructions that touch a watchpoint; runners drain it.
+    pub watch_hits: Vec<WatchHit>,
+    // While set, every memory and video memory write records the value it
+    // overwrites so the instruction can be undone.
+    pub journal: Option<Journal>,
+    // Where the gamepad device gets its input, polled before every
+    // instruction. Without one the gamepad is disconnected.
+    pub gamepad_backend: Option<Box<dyn gamepad::Backend>>,
+    // Records every input event delivered to the devices.
+    pub input_recorder: Option<Recorder>,
+    // Delivers recorded input events before the instructions they preceded.
+    pub input_replay: Option<Replay>,
 }
 
 #[repr(u16)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpcodeType {
     Load = 0x01,
     Store = 0x02,
@@ -71,6 +116,27 @@ pub enum OpcodeType {
     Ret = 0x16,
 
     Load8 = 0x17,
+    Sys = 0x18,
+    //Conditional jumps, signed (l/g) and unsigned (b/a)
+    Jl = 0x19,
+    Jg = 0x1A,
+    Jle = 0x1B,
+    Jge = 0x1C,
+    Jb = 0x1D,
+    Ja = 0x1E,
+    Jc = 0x1F,
+    Jo = 0x20,
+    Pushf = 0x21,
+    Popf = 0x22,
+    Store8 = 0x23,
+    //Device ports
+    In = 0x24,
+    Out = 0x25,
+    //Interrupts
+    Int = 0x26,
+    Iret = 0x27,
+    Cli = 0x28,
+    Sti = 0x29,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -104,10 +170,13 @@ pub enum Register {
     V15 = 0x2010, //Quadrilateral point 4 y
     //Keycode
     K0 = 0x3001,
+    //Segment registers
+    CS = 0x4001, // Code segment, fixed to the code region
+    DS = 0x4002, // Data segment used by load, store and load8
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FunctionCall {
     SetPixel = 0x13,
     DrawLine = 0x14,
@@ -127,39 +196,83 @@ pub struct Opcode {
     pub arg2: Option<OpcodeArgument>,
 }
 
-#[repr(transparent)]
-#[derive(Debug, Clone, Copy)]
-pub struct InvalidOpcode(pub u16);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmErrorKind {
+    InvalidOpcode(u16),
+    InvalidRegister(u16),
+    InvalidFunctionCall(u16),
+    BadOperand(OpcodeType),
+    MemoryFault(usize),
+    DivisionByZero,
+    StackOverflow,
+    StackUnderflow,
+    InvalidPort(u16),
+    UnhandledInterrupt(u16),
+    FunctionFailed(FunctionCall),
+}
 
-impl Display for InvalidOpcode {
+impl Display for VmErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Invalid Opcode: {}", self.0)
+        match self {
+            VmErrorKind::InvalidOpcode(opcode) => write!(f, "Invalid Opcode: {:#06X}", opcode),
+            VmErrorKind::InvalidRegister(register) => {
+                write!(f, "Invalid Register: {:#06X}", register)
+            }
+            VmErrorKind::InvalidFunctionCall(function) => {
+                write!(f, "Invalid Function Call: {:#06X}", function)
+            }
+            VmErrorKind::BadOperand(opcode_type) => {
+                write!(f, "Bad operand for {:?}", opcode_type)
+            }
+            VmErrorKind::MemoryFault(address) => write!(f, "Memory fault at {:#X}", address),
+            VmErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            VmErrorKind::StackOverflow => write!(f, "Stack overflow"),
+            VmErrorKind::StackUnderflow => write!(f, "Stack underflow"),
+            VmErrorKind::InvalidPort(port) => write!(f, "Invalid port: {:#06X}", port),
+            VmErrorKind::UnhandledInterrupt(vector) => {
+                write!(f, "Unhandled interrupt: {:#04X}", vector)
+            }
+            VmErrorKind::FunctionFailed(function) => {
+                write!(f, "Built-in function {} failed", function)
+            }
+        }
     }
 }
 
-pub struct InvalidOpcodeString(pub String);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmError {
+    pub pc: u16,
+    pub kind: VmErrorKind,
+}
 
-impl Display for InvalidOpcodeString {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Invalid Opcode: {}", self.0)
+impl VmError {
+    pub fn new(pc: u16, kind: VmErrorKind) -> Self {
+        Self { pc, kind }
     }
 }
 
-#[repr(transparent)]
-#[derive(Debug, Clone, Copy)]
-pub struct InvalidFunctionCall(pub u16);
-
-impl Display for InvalidFunctionCall {
+impl Display for VmError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Invalid Function Call: {}", self.0)
+        write!(f, "{} (pc = {:#06X})", self.kind, self.pc)
     }
 }
 
-pub struct InvalidRegister(pub u16);
+impl std::error::Error for VmError {}
 
-impl Display for InvalidRegister {
+/// What one step of the CPU did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Executed {
+    Instruction,
+    // Entered the handler of this hardware interrupt vector instead of
+    // executing an instruction.
+    Interrupt(u8),
+}
+
+pub struct InvalidOpcodeString(pub String);
+
+impl Display for InvalidOpcodeString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Invalid Register: {}", self.0)
+        write!(f, "Invalid Opcode: {}", self.0)
     }
 }
 
@@ -179,28 +292,41 @@ impl Display for InvalidFunctionCallString {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum OpcodeArgument {
     Register(Register),
     Immediate(u16),
 }
 
 impl MicroCVMCpu {
-    pub fn empty() -> Self {
+    pub fn new(memory_size: usize, framebuffer_width: usize, framebuffer_height: usize) -> Self {
+        let mut registers = [0; REGISTER_COUNT];
+        registers[Register::index(Register::CS)] = CODE_SEGMENT;
+        registers[Register::index(Register::DS)] = CODE_SEGMENT;
+
         Self {
-            memory: vec![0; MEM_TOTAL / 2],
+            memory: vec![0; memory_size / 2],
             video_memory: vec![super::types::Color::new(0, 0, 0); VIDEO_MEMORY],
-            registers: [0; REGISTER_COUNT],
+            registers,
             pc: 0,
-            sp: STACK_OFFSET as u16,
+            sp: STACK_WORDS,
             flags: 0,
-            framebuffer_width: 768,
-            framebuffer_height: 576,
+            cycles: 0,
+            devices: Devices::default(),
+            framebuffer_width,
+            framebuffer_height,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            journal: None,
+            gamepad_backend: None,
+            input_recorder: None,
+            input_replay: None,
         }
     }
     pub fn get_opcode_argument_count(opcode_type: OpcodeType) -> u8 {
         match opcode_type {
             OpcodeType::Load => 2,
+            OpcodeType::Store => 2,
             OpcodeType::Inc => 1,
             OpcodeType::Mov => 2,
             OpcodeType::Jmp => 1,
@@ -221,23 +347,73 @@ impl MicroCVMCpu {
             OpcodeType::Push => 1,
             OpcodeType::Pop => 1,
             OpcodeType::Load8 => 2,
+            OpcodeType::Store8 => 2,
+            OpcodeType::In => 2,
+            OpcodeType::Out => 2,
+            OpcodeType::Int => 1,
+            OpcodeType::Sys => 1,
+            OpcodeType::Jl => 1,
+            OpcodeType::Jg => 1,
+            OpcodeType::Jle => 1,
+            OpcodeType::Jge => 1,
+            OpcodeType::Jb => 1,
+            OpcodeType::Ja => 1,
+            OpcodeType::Jc => 1,
+            OpcodeType::Jo => 1,
             _ => 0,
         }
     }
 
-    pub fn create_opcode(&mut self) -> Opcode {
+    // Rough costs in clock cycles: register operations take one cycle, memory
+    // and the stack more, and `sys` stands in for a whole drawing routine.
+    pub fn get_opcode_cycles(opcode_type: OpcodeType) -> u8 {
+        match opcode_type {
+            OpcodeType::Load | OpcodeType::Store => 3,
+            OpcodeType::Load8 | OpcodeType::Store8 => 3,
+            OpcodeType::In | OpcodeType::Out => 3,
+            OpcodeType::Mul => 4,
+            OpcodeType::Div => 8,
+            OpcodeType::Push | OpcodeType::Pop => 2,
+            OpcodeType::Pushf | OpcodeType::Popf => 2,
+            OpcodeType::Call | OpcodeType::Ret => 4,
+            OpcodeType::Int => 8,
+            OpcodeType::Iret => 6,
+            OpcodeType::Jmp
+            | OpcodeType::Je
+            | OpcodeType::Jne
+            | OpcodeType::Jl
+            | OpcodeType::Jg
+            | OpcodeType::Jle
+            | OpcodeType::Jge
+            | OpcodeType::Jb
+            | OpcodeType::Ja
+            | OpcodeType::Jc
+            | OpcodeType::Jo => 2,
+            OpcodeType::Sys => 64,
+            _ => 1,
+        }
+    }
+
+    pub fn create_opcode(&self) -> Result<Opcode, VmError> {
+        self.decode_at(self.pc)
+    }
+
+    // Decodes the instruction at `pc` in the code segment without executing it.
+    pub fn decode_at(&self, pc: u16) -> Result<Opcode, VmError> {
+        let fault = |kind| VmError::new(pc, kind);
         let mut current_instruction = Opcode::empty();
 
-        let opcode_word = self.memory[self.pc as usize];
+        let opcode_word = self.read_memory(self.code_address(pc)).map_err(fault)?;
         let opcode_byte = (opcode_word & 0xFF) as u8;
-        current_instruction.opcode_type =
-            OpcodeType::try_from(opcode_byte as u16).unwrap_or(OpcodeType::Nop);
+        current_instruction.opcode_type = OpcodeType::try_from(opcode_byte as u16).map_err(fault)?;
 
         current_instruction.argument_count =
             Self::get_opcode_argument_count(current_instruction.opcode_type) as u16;
 
         if current_instruction.argument_count >= 1 {
-            let arg1 = self.memory[(self.pc + 1) as usize];
+            let arg1 = self
+                .read_memory(self.code_address(pc.wrapping_add(1)))
+                .map_err(fault)?;
             current_instruction.arg1 = Some(
                 Register::try_from(arg1)
                     .map(OpcodeArgument::Register)
@@ -246,7 +422,9 @@ impl MicroCVMCpu {
         }
 
         if current_instruction.argument_count >= 2 {
-            let arg2 = self.memory[(self.pc + 2) as usize];
+            let arg2 = self
+                .read_memory(self.code_address(pc.wrapping_add(2)))
+                .map_err(fault)?;
             current_instruction.arg2 = Some(
                 Register::try_from(arg2)
                     .map(OpcodeArgument::Register)
@@ -254,338 +432,795 @@ impl MicroCVMCpu {
             );
         }
 
-        current_instruction
+        Ok(current_instruction)
     }
 
-    pub fn execute_instruction(&mut self) -> u16 {
-        let opcode = self.create_opcode();
+    pub fn execute_instruction(&mut self) -> Result<Executed, VmError> {
+        if let Some(replay) = &mut self.input_replay {
+            for event in replay.due(self.cycles) {
+                self.input(event);
+            }
+        }
+
+        let cycles = self.cycles;
+        if let Some(state) = self
+            .gamepad_backend
+            .as_mut()
+            .map(|backend| backend.poll(cycles))
+            && state != self.devices.gamepad.state
+        {
+            self.devices_mut().gamepad_update(state);
+        }
+
+        // Entering a handler is a step of its own, so the handler's first
+        // instruction is traced, stepped to and stopped at like any other.
+        if self.get_flag(FLAG_INTERRUPT) {
+            let pc = self.pc;
+            if let Some(vector) = self
+                .deliver_interrupt()
+                .map_err(|kind| VmError::new(pc, kind))?
+            {
+                self.tick_devices();
+                return Ok(Executed::Interrupt(vector));
+            }
+        }
+
+        let opcode = self.create_opcode()?;
+        let pc = self.pc;
+        let opcode_length = opcode.argument_count + 1;
+        let first_hit = self.watch_hits.len();
+
+        // Jumps, calls and returns overwrite the already advanced pc.
+        self.pc = pc.wrapping_add(opcode_length);
+
+        if let Err(kind) = self.execute_opcode(&opcode) {
+            self.pc = pc;
+            self.watch_hits.truncate(first_hit);
+            return Err(VmError::new(pc, kind));
+        }
+
+        for hit in &mut self.watch_hits[first_hit..] {
+            hit.pc = pc;
+        }
+
+        self.cycles += Self::get_opcode_cycles(opcode.opcode_type) as u64;
+        self.tick_devices();
 
+        Ok(Executed::Instruction)
+    }
+
+    fn execute_opcode(&mut self, opcode: &Opcode) -> Result<(), VmErrorKind> {
         match opcode.opcode_type {
             OpcodeType::Inc => {
-                if let Some(OpcodeArgument::Register(reg)) = opcode.arg1 {
-                    self.registers[Register::index(reg) as usize] += 1;
-                }
+                let reg = opcode.destination(opcode.arg1)?;
+                let index = Register::index(reg);
+                self.registers[index] = self.registers[index].wrapping_add(1);
             }
 
             OpcodeType::Mov => {
-                if let Some(OpcodeArgument::Register(dst)) = opcode.arg1 {
-                    let dst_index = Register::index(dst) as usize;
-
-                    match opcode.arg2 {
-                        Some(OpcodeArgument::Immediate(imm)) => {
-                            self.registers[dst_index] = imm;
-                        }
-                        Some(OpcodeArgument::Register(src)) => {
-                            let src_index = Register::index(src) as usize;
-                            self.registers[dst_index] = self.registers[src_index];
-                        }
-                        _ => {
-
-                        }
-                    }
-                }
+                let dst = opcode.destination(opcode.arg1)?;
+                self.registers[Register::index(dst)] = self.operand_value(opcode, opcode.arg2)?;
             }
 
             OpcodeType::Add => {
-                if let Some(OpcodeArgument::Register(dst)) = opcode.arg1 {
-                    let dst_index = Register::index(dst) as usize;
-
-                    match opcode.arg2 {
-                        Some(OpcodeArgument::Immediate(imm)) => {
-                            self.registers[dst_index] += imm;
-                        }
-                        Some(OpcodeArgument::Register(src)) => {
-                            let src_index = Register::index(src) as usize;
-                            self.registers[dst_index] += self.registers[src_index];
-                        }
-                        _ => {
-
-                        }
-                    }
-                }
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                self.registers[dst_index] = self.add_with_flags(self.registers[dst_index], value);
             }
 
-
             OpcodeType::Sub => {
-                if let Some(OpcodeArgument::Register(dst)) = opcode.arg1 {
-                    let dst_index = Register::index(dst) as usize;
-
-                    match opcode.arg2 {
-                        Some(OpcodeArgument::Immediate(imm)) => {
-                            self.registers[dst_index] -= imm;
-                        }
-                        Some(OpcodeArgument::Register(src)) => {
-                            let src_index = Register::index(src) as usize;
-                            self.registers[dst_index] -= self.registers[src_index];
-                        }
-                        _ => {
-
-                        }
-                    }
-                }
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                self.registers[dst_index] = self.sub_with_flags(self.registers[dst_index], value);
             }
 
             OpcodeType::Div => {
-                if let (Some(OpcodeArgument::Register(dst)), Some(OpcodeArgument::Immediate(imm))) =
-                    (opcode.arg1, opcode.arg2)
-                {
-                    self.registers[Register::index(dst) as usize] /= imm;
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let value = self.operand_value(opcode, opcode.arg2)?;
+
+                if value == 0 {
+                    return Err(VmErrorKind::DivisionByZero);
                 }
+
+                self.registers[dst_index] /= value;
             }
 
             OpcodeType::Mul => {
-                if let (Some(OpcodeArgument::Register(dst)), Some(OpcodeArgument::Immediate(imm))) =
-                    (opcode.arg1, opcode.arg2)
-                {
-                    self.registers[Register::index(dst) as usize] *= imm;
-                }
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                let product = self.registers[dst_index] as u32 * value as u32;
+                let result = product as u16;
+                let truncated = product > u16::MAX as u32;
+                self.set_result_flags(result, truncated, truncated);
+                self.registers[dst_index] = result;
+            }
+
+            OpcodeType::And | OpcodeType::Or | OpcodeType::Xor => {
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                let result = match opcode.opcode_type {
+                    OpcodeType::And => self.registers[dst_index] & value,
+                    OpcodeType::Or => self.registers[dst_index] | value,
+                    _ => self.registers[dst_index] ^ value,
+                };
+                self.set_result_flags(result, false, false);
+                self.registers[dst_index] = result;
+            }
+
+            OpcodeType::Not => {
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let result = !self.registers[dst_index];
+                self.set_result_flags(result, false, false);
+                self.registers[dst_index] = result;
+            }
+
+            OpcodeType::Shl | OpcodeType::Shr => {
+                let dst_index = Register::index(opcode.destination(opcode.arg1)?);
+                let count = self.operand_value(opcode, opcode.arg2)?;
+                self.registers[dst_index] = self.shift_with_flags(
+                    self.registers[dst_index],
+                    count,
+                    opcode.opcode_type == OpcodeType::Shl,
+                );
             }
 
             OpcodeType::Load => {
-                if let Some(OpcodeArgument::Register(dst)) = opcode.arg1 {
-                    let dst_index = Register::index(dst) as usize;
-
-                    match opcode.arg2 {
-                        Some(OpcodeArgument::Immediate(imm)) => {
-                            self.registers[dst_index] = self.memory[imm as usize];
-                        }
-                        Some(OpcodeArgument::Register(src)) => {
-                            let src_index = Register::index(src) as usize;
-                            let addr = self.registers[src_index] as usize;
-                            self.registers[dst_index] = self.memory[addr];
-                        }
-                        _ => {
-
-                        }
-                    }
-                }
+                let dst = opcode.destination(opcode.arg1)?;
+                let addr = self.operand_value(opcode, opcode.arg2)?;
+                self.note_access(self.data_address(addr), 2, Access::Read);
+                self.registers[Register::index(dst)] = self.read_data_word(addr)?;
             }
 
             OpcodeType::Store => {
-                if let (
-                    Some(OpcodeArgument::Immediate(addr)),
-                    Some(OpcodeArgument::Register(src)),
-                ) = (opcode.arg1, opcode.arg2)
-                {
-                    self.memory[(addr / 2) as usize] =
-                        self.registers[Register::index(src) as usize];
-                }
+                let addr = self.operand_value(opcode, opcode.arg1)?;
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                self.note_access(self.data_address(addr), 2, Access::Write);
+                self.write_data_word(addr, value)?;
             }
 
             OpcodeType::Jmp => {
-                if let Some(OpcodeArgument::Immediate(target)) = opcode.arg1 {
-                    self.pc = target;
-                }
+                self.pc = opcode.immediate(opcode.arg1)?;
             }
 
             OpcodeType::Cmp => {
-                if let (Some(OpcodeArgument::Register(dst)), Some(OpcodeArgument::Immediate(imm))) =
-                    (opcode.arg1, opcode.arg2)
-                {
-                    let reg_value = self.registers[Register::index(dst)];
-                    let is_equal = reg_value == imm;
-                    self.set_flag(FLAG_ZERO, is_equal);
-                }
+                let dst = opcode.register(opcode.arg1)?;
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                self.sub_with_flags(self.registers[Register::index(dst)], value);
             }
 
-            OpcodeType::Je => {
-                if let Some(OpcodeArgument::Immediate(target)) = opcode.arg1 {
-                    if self.get_flag(FLAG_ZERO) == true {
-                        self.pc = target;
-                    }
+            OpcodeType::Je
+            | OpcodeType::Jne
+            | OpcodeType::Jl
+            | OpcodeType::Jg
+            | OpcodeType::Jle
+            | OpcodeType::Jge
+            | OpcodeType::Jb
+            | OpcodeType::Ja
+            | OpcodeType::Jc
+            | OpcodeType::Jo => {
+                let target = opcode.immediate(opcode.arg1)?;
+                if self.condition_met(opcode.opcode_type) {
+                    self.pc = target;
                 }
             }
 
-            OpcodeType::Jne => {
-                if let Some(OpcodeArgument::Immediate(target)) = opcode.arg1 {
-                    if self.get_flag(FLAG_ZERO) == false {
-                        self.pc = target;
-                    }
-                }
+            OpcodeType::Call => {
+                let target = opcode.immediate(opcode.arg1)?;
+                self.push_word(self.pc)?;
+                self.pc = target;
             }
 
-            OpcodeType::Call => {
-                if let Some(OpcodeArgument::Immediate(target)) = opcode.arg1 {
-                    if target == FunctionCall::ClearScreen as u16 {
-                        let _ = super::screen::DrawCommand::clear_screen(self);
-                    }
-                    if target == FunctionCall::FillScreen as u16 {
-                        let color = super::types::Color::new(
-                            self.registers[Register::index(Register::V0) as usize] as u8,
-                            self.registers[Register::index(Register::V1) as usize] as u8,
-                            self.registers[Register::index(Register::V2) as usize] as u8,
-                        );
-                        let _ = super::screen::DrawCommand::fill_screen(self, color);
-                    }
-                    if target == FunctionCall::DrawLine as u16 {
-                        let color = super::types::Color::new(
-                            self.registers[Register::index(Register::V0) as usize] as u8,
-                            self.registers[Register::index(Register::V1) as usize] as u8,
-                            self.registers[Register::index(Register::V2) as usize] as u8,
-                        );
-                        let line_start = super::types::Point::new(
-                            self.registers[Register::index(Register::V4) as usize] as isize,
-                            self.registers[Register::index(Register::V5) as usize] as isize,
-                        );
-                        let line_end = super::types::Point::new(
-                            self.registers[Register::index(Register::V6) as usize] as isize,
-                            self.registers[Register::index(Register::V7) as usize] as isize,
-                        );
-                        let thickness =
-                            self.registers[Register::index(Register::V3) as usize] as isize;
-                        let _ = super::screen::DrawCommand::draw_line(
-                            self, color, line_start, line_end, thickness,
-                        );
-                    }
-                    if target == FunctionCall::LoadBMP as u16 {
-                        let point = super::types::Point::new(
-                            self.registers[Register::index(Register::V4) as usize] as isize,
-                            self.registers[Register::index(Register::V5) as usize] as isize,
-                        );
-                        let bmp_file_path = "../../examples/bg.bmp";
-                        let bmp_bytes = std::fs::read(bmp_file_path).unwrap();
-                        let _ = super::screen::DrawCommand::draw_bmp(self, &bmp_bytes, point);
-                    }
-                    if target == FunctionCall::FillRect as u16 {
-                        let color = Color::new(
-                            self.registers[Register::index(Register::V0) as usize] as u8,
-                            self.registers[Register::index(Register::V1) as usize] as u8,
-                            self.registers[Register::index(Register::V2) as usize] as u8,
-                        );
-                        let center = Point::new(
-                            self.registers[Register::index(Register::V4) as usize] as isize,
-                            self.registers[Register::index(Register::V5) as usize] as isize,
-                        );
-                        let size = self.registers[Register::index(Register::V6) as usize] as isize;
-                        let _ = DrawCommand::fill_rect(self, color, center, size);
-                    }
-                    if target == FunctionCall::FillQuad as u16 {
-                        let color = Color::new(
-                            self.registers[Register::index(Register::V0) as usize] as u8,
-                            self.registers[Register::index(Register::V1) as usize] as u8,
-                            self.registers[Register::index(Register::V2) as usize] as u8,
-                        );
-                        let p1 = Point::new(
-                            self.registers[Register::index(Register::V8) as usize] as isize,
-                            self.registers[Register::index(Register::V9) as usize] as isize,
-                        );
-                        let p2 = Point::new(
-                            self.registers[Register::index(Register::V10) as usize] as isize,
-                            self.registers[Register::index(Register::V11) as usize] as isize,
-                        );
-                        let p3 = Point::new(
-                            self.registers[Register::index(Register::V12) as usize] as isize,
-                            self.registers[Register::index(Register::V13) as usize] as isize,
-                        );
-                        let p4 = Point::new(
-                            self.registers[Register::index(Register::V14) as usize] as isize,
-                            self.registers[Register::index(Register::V15) as usize] as isize,
-                        );
-                        let _ = DrawCommand::fill_quad(self, color, p1, p2, p3, p4);
-                    }
-                    if target == FunctionCall::DrawCharacter as u16 {
-                        let character = self.registers[Register::index(Register::V3) as usize] as u8 as char;
-                        let character_position = Point::new(
-                            self.registers[Register::index(Register::V4) as usize] as isize,
-                            self.registers[Register::index(Register::V5) as usize] as isize,
-                        );
-                        let character_color = Color::new(
-                            self.registers[Register::index(Register::V0) as usize] as u8,
-                            self.registers[Register::index(Register::V1) as usize] as u8,
-                            self.registers[Register::index(Register::V2) as usize] as u8,
-                        );
-                        let _ = DrawCommand::draw_character(self, character, character_position, character_color);
-                    }
-                }
+            OpcodeType::Ret => {
+                self.pc = self.pop_word()?;
             }
 
-            OpcodeType::Push => {
-                if let Some(OpcodeArgument::Register(target)) = opcode.arg1 {
-                    self.sp -= 1;
+            OpcodeType::Int => {
+                let vector = opcode.immediate(opcode.arg1)?;
+                if vector >= INTERRUPT_VECTORS {
+                    return Err(VmErrorKind::BadOperand(opcode.opcode_type));
+                }
 
-                    self.memory[self.sp as usize] = target as u16;
+                if !self.enter_interrupt(vector)? {
+                    return Err(VmErrorKind::UnhandledInterrupt(vector));
                 }
             }
 
+            OpcodeType::Iret => {
+                self.pc = self.pop_word()?;
+                self.registers[Register::index(Register::CS)] = self.pop_word()?;
+                self.flags = self.pop_word()?;
+            }
+
+            OpcodeType::Cli => self.set_flag(FLAG_INTERRUPT, false),
+
+            OpcodeType::Sti => self.set_flag(FLAG_INTERRUPT, true),
+
+            OpcodeType::Sys => {
+                let function = FunctionCall::try_from(opcode.immediate(opcode.arg1)?)?;
+                let watched_pixels = self.watched_pixels();
+                self.call_function(function)?;
+                self.note_video_writes(&watched_pixels);
+            }
+
+            OpcodeType::Push => {
+                let value = self.operand_value(opcode, opcode.arg1)?;
+                self.push_word(value)?;
+            }
+
             OpcodeType::Pop => {
-                if let Some(OpcodeArgument::Register(target)) = opcode.arg1 {
-                    let value = self.memory[self.sp as usize];
+                let target = opcode.destination(opcode.arg1)?;
+                self.registers[Register::index(target)] = self.pop_word()?;
+            }
 
-                    self.sp += 1;
+            OpcodeType::Pushf => {
+                self.push_word(self.flags)?;
+            }
 
-                    self.registers[Register::index(target) as usize] = value;
-                }
+            OpcodeType::Popf => {
+                self.flags = self.pop_word()?;
             }
 
             OpcodeType::Load8 => {
-                if let Some(OpcodeArgument::Register(dst)) = opcode.arg1 {
-                    let dst_index = Register::index(dst) as usize;
-
-                    match opcode.arg2 {
-                        Some(OpcodeArgument::Immediate(imm)) => {
-                            let word = self.memory[imm as usize / 2];
-                            let byte = if imm % 2 == 0 {
-                                word & 0x00FF
-                            } else {
-                                (word >> 8) & 0x00FF
-                            };
-                            self.registers[dst_index] = byte;
-                        }
-                        Some(OpcodeArgument::Register(src)) => {
-                            let src_index = Register::index(src) as usize;
-                            let addr = self.registers[src_index] as usize;
-                            let word = self.memory[addr / 2];
-                            let byte = if addr % 2 == 0 {
-                                word & 0x00FF
-                            } else {
-                                (word >> 8) & 0x00FF
-                            };
-                            self.registers[dst_index] = byte;
-                        }
-                        _ => {
-
-                        }
-                    }
-                }
+                let dst = opcode.destination(opcode.arg1)?;
+                let addr = self.operand_value(opcode, opcode.arg2)?;
+                self.note_access(self.data_address(addr), 1, Access::Read);
+                self.registers[Register::index(dst)] =
+                    self.read_byte(self.data_address(addr))? as u16;
             }
 
+            OpcodeType::Store8 => {
+                let addr = self.operand_value(opcode, opcode.arg1)?;
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                self.note_access(self.data_address(addr), 1, Access::Write);
+                self.write_byte(self.data_address(addr), value as u8)?;
+            }
+
+            OpcodeType::In => {
+                let dst = opcode.destination(opcode.arg1)?;
+                let port = self.operand_value(opcode, opcode.arg2)?;
+                self.registers[Register::index(dst)] = self.devices_mut().read(port)?;
+            }
+
+            OpcodeType::Out => {
+                let port = self.operand_value(opcode, opcode.arg1)?;
+                let value = self.operand_value(opcode, opcode.arg2)?;
+                let cycles = self.cycles;
+                self.devices_mut().write(port, value, cycles)?;
+            }
 
             _ => {}
         }
 
-        opcode.argument_count + 1
+        Ok(())
+    }
+
+    // The devices, to change them. The first change an instruction makes
+    // journals the devices as they were, like `write_memory` does for words.
+    fn devices_mut(&mut self) -> &mut Devices {
+        if let Some(journal) = &mut self.journal
+            && journal.devices.is_none()
+        {
+            journal.devices = Some(self.devices.clone());
+        }
+
+        &mut self.devices
+    }
+
+    // Most instructions end without a timer period or frame ending, and then
+    // leave the devices alone.
+    fn tick_devices(&mut self) {
+        let cycles = self.cycles;
+        if self.devices.due(cycles) {
+            self.devices_mut().tick(cycles);
+        }
+    }
+
+    // Enters the handler of the highest priority request that has one. A
+    // request stays pending until its handler is entered, so one without a
+    // handler, or one that faults on a full stack, is not lost.
+    fn deliver_interrupt(&mut self) -> Result<Option<u8>, VmErrorKind> {
+        for line in self.devices.interrupts.requests() {
+            let vector = interrupts::IRQ_BASE + line;
+            if self.enter_interrupt(vector as u16)? {
+                self.devices_mut().interrupts.acknowledge(line);
+                return Ok(Some(vector));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Pushes the flags, `cs` and `pc`, disables interrupts and jumps to the
+    // handler of `vector`. Returns false when no handler is installed.
+    fn enter_interrupt(&mut self, vector: u16) -> Result<bool, VmErrorKind> {
+        let entry = IVT_OFFSET / 2 + vector as usize * 2;
+        let offset = self.read_memory(entry)?;
+        let segment = self.read_memory(entry + 1)?;
+
+        if offset == 0 && segment == 0 {
+            return Ok(false);
+        }
+
+        // Fail before pushing anything so a fault leaves the stack untouched.
+        if self.sp < 3 {
+            return Err(VmErrorKind::StackOverflow);
+        }
+
+        self.push_word(self.flags)?;
+        self.push_word(self.registers[Register::index(Register::CS)])?;
+        self.push_word(self.pc)?;
+
+        self.set_flag(FLAG_INTERRUPT, false);
+        self.registers[Register::index(Register::CS)] = segment;
+        self.pc = offset;
+        self.cycles += INTERRUPT_CYCLES;
+
+        Ok(true)
+    }
+
+    fn add_with_flags(&mut self, lhs: u16, rhs: u16) -> u16 {
+        let (result, carry) = lhs.overflowing_add(rhs);
+        let (_, overflow) = (lhs as i16).overflowing_add(rhs as i16);
+        self.set_result_flags(result, carry, overflow);
+        result
+    }
+
+    fn sub_with_flags(&mut self, lhs: u16, rhs: u16) -> u16 {
+        let (result, borrow) = lhs.overflowing_sub(rhs);
+        let (_, overflow) = (lhs as i16).overflowing_sub(rhs as i16);
+        self.set_result_flags(result, borrow, overflow);
+        result
+    }
+
+    // Carry holds the last bit shifted out; overflow is set when the sign bit
+    // changes. Shifting by 16 or more clears the register.
+    fn shift_with_flags(&mut self, value: u16, count: u16, left: bool) -> u16 {
+        let (result, carry) = if count == 0 {
+            (value, false)
+        } else if left {
+            let result = value.checked_shl(count as u32).unwrap_or(0);
+            let carry = count <= 16 && value & (1 << (16 - count)) != 0;
+            (result, carry)
+        } else {
+            let result = value.checked_shr(count as u32).unwrap_or(0);
+            let carry = count <= 16 && value & (1 << (count - 1)) != 0;
+            (result, carry)
+        };
+
+        let overflow = (value ^ result) & 0x8000 != 0;
+        self.set_result_flags(result, carry, overflow);
+        result
+    }
+
+    fn set_result_flags(&mut self, result: u16, carry: bool, overflow: bool) {
+        self.set_flag(FLAG_ZERO, result == 0);
+        self.set_flag(FLAG_SIGN, result & 0x8000 != 0);
+        self.set_flag(FLAG_CARRY, carry);
+        self.set_flag(FLAG_OVERFLOW, overflow);
+    }
+
+    fn condition_met(&self, opcode_type: OpcodeType) -> bool {
+        let zero = self.get_flag(FLAG_ZERO);
+        let carry = self.get_flag(FLAG_CARRY);
+        let less = self.get_flag(FLAG_SIGN) != self.get_flag(FLAG_OVERFLOW);
+
+        match opcode_type {
+            OpcodeType::Je => zero,
+            OpcodeType::Jne => !zero,
+            OpcodeType::Jl => less,
+            OpcodeType::Jg => !zero && !less,
+            OpcodeType::Jle => zero || less,
+            OpcodeType::Jge => !less,
+            OpcodeType::Jb | OpcodeType::Jc => carry,
+            OpcodeType::Ja => !zero && !carry,
+            OpcodeType::Jo => self.get_flag(FLAG_OVERFLOW),
+            _ => false,
+        }
     }
 
-    pub fn read_memory_from_file(&mut self, file_path: &str) -> io::Result<usize> {
+    fn call_function(&mut self, function: FunctionCall) -> Result<(), VmErrorKind> {
+        match function {
+            FunctionCall::SetPixel => {}
+            FunctionCall::ClearScreen => {
+                super::screen::DrawCommand::clear_screen(self);
+            }
+            FunctionCall::FillScreen => {
+                let color = super::types::Color::new(
+                    self.registers[Register::index(Register::V0)] as u8,
+                    self.registers[Register::index(Register::V1)] as u8,
+                    self.registers[Register::index(Register::V2)] as u8,
+                );
+                super::screen::DrawCommand::fill_screen(self, color);
+            }
+            FunctionCall::DrawLine => {
+                let color = super::types::Color::new(
+                    self.registers[Register::index(Register::V0)] as u8,
+                    self.registers[Register::index(Register::V1)] as u8,
+                    self.registers[Register::index(Register::V2)] as u8,
+                );
+                let line_start = super::types::Point::new(
+                    self.registers[Register::index(Register::V4)] as isize,
+                    self.registers[Register::index(Register::V5)] as isize,
+                );
+                let line_end = super::types::Point::new(
+                    self.registers[Register::index(Register::V6)] as isize,
+                    self.registers[Register::index(Register::V7)] as isize,
+                );
+                let thickness = self.registers[Register::index(Register::V3)] as isize;
+                super::screen::DrawCommand::draw_line(self, color, line_start, line_end, thickness);
+            }
+            FunctionCall::LoadBMP => {
+                let point = super::types::Point::new(
+                    self.registers[Register::index(Register::V4)] as isize,
+                    self.registers[Register::index(Register::V5)] as isize,
+                );
+                let bmp_file_path = "examples/bg.bmp";
+                let bmp_bytes = std::fs::read(bmp_file_path)
+                    .map_err(|_| VmErrorKind::FunctionFailed(function))?;
+                super::screen::DrawCommand::draw_bmp(self, &bmp_bytes, point)
+                    .ok_or(VmErrorKind::FunctionFailed(function))?;
+            }
+            FunctionCall::FillRect => {
+                let color = Color::new(
+                    self.registers[Register::index(Register::V0)] as u8,
+                    self.registers[Register::index(Register::V1)] as u8,
+                    self.registers[Register::index(Register::V2)] as u8,
+                );
+                let center = Point::new(
+                    self.registers[Register::index(Register::V4)] as isize,
+                    self.registers[Register::index(Register::V5)] as isize,
+                );
+                let size = self.registers[Register::index(Register::V6)] as isize;
+                DrawCommand::fill_rect(self, color, center, size);
+            }
+            FunctionCall::FillQuad => {
+                let color = Color::new(
+                    self.registers[Register::index(Register::V0)] as u8,
+                    self.registers[Register::index(Register::V1)] as u8,
+                    self.registers[Register::index(Register::V2)] as u8,
+                );
+                let p1 = Point::new(
+                    self.registers[Register::index(Register::V8)] as isize,
+                    self.registers[Register::index(Register::V9)] as isize,
+                );
+                let p2 = Point::new(
+                    self.registers[Register::index(Register::V10)] as isize,
+                    self.registers[Register::index(Register::V11)] as isize,
+                );
+                let p3 = Point::new(
+                    self.registers[Register::index(Register::V12)] as isize,
+                    self.registers[Register::index(Register::V13)] as isize,
+                );
+                let p4 = Point::new(
+                    self.registers[Register::index(Register::V14)] as isize,
+                    self.registers[Register::index(Register::V15)] as isize,
+                );
+                DrawCommand::fill_quad(self, color, p1, p2, p3, p4);
+            }
+            FunctionCall::DrawCharacter => {
+                let character = self.registers[Register::index(Register::V3)] as u8 as char;
+                let character_position = Point::new(
+                    self.registers[Register::index(Register::V4)] as isize,
+                    self.registers[Register::index(Register::V5)] as isize,
+                );
+                let character_color = Color::new(
+                    self.registers[Register::index(Register::V0)] as u8,
+                    self.registers[Register::index(Register::V1)] as u8,
+                    self.registers[Register::index(Register::V2)] as u8,
+                );
+                DrawCommand::draw_character(self, character, character_position, character_color);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn operand_value(
+        &self,
+        opcode: &Opcode,
+        argument: Option<OpcodeArgument>,
+    ) -> Result<u16, VmErrorKind> {
+        match argument {
+            Some(OpcodeArgument::Immediate(imm)) => Ok(imm),
+            Some(OpcodeArgument::Register(reg)) => Ok(self.registers[Register::index(reg)]),
+            None => Err(VmErrorKind::BadOperand(opcode.opcode_type)),
+        }
+    }
+
+    fn segment_base(&self, segment: Register) -> usize {
+        (self.registers[Register::index(segment)] as usize) << PARAGRAPH_SHIFT
+    }
+
+    pub fn code_address(&self, offset: u16) -> usize {
+        self.segment_base(Register::CS) + offset as usize
+    }
+
+    // Data is byte addressed: `offset` counts bytes from the start of DS.
+    pub fn data_address(&self, offset: u16) -> usize {
+        self.segment_base(Register::DS) * 2 + offset as usize
+    }
+
+    pub fn read_memory(&self, address: usize) -> Result<u16, VmErrorKind> {
+        self.memory
+            .get(address)
+            .copied()
+            .ok_or(VmErrorKind::MemoryFault(address * 2))
+    }
+
+    pub fn write_memory(&mut self, address: usize, value: u16) -> Result<(), VmErrorKind> {
+        let word = self
+            .memory
+            .get_mut(address)
+            .ok_or(VmErrorKind::MemoryFault(address * 2))?;
+
+        if let Some(journal) = &mut self.journal {
+            journal.memory.push((address, *word));
+        }
+
+        *word = value;
+        Ok(())
+    }
+
+    pub fn read_byte(&self, address: usize) -> Result<u8, VmErrorKind> {
+        let word = self
+            .read_memory(address / 2)
+            .map_err(|_| VmErrorKind::MemoryFault(address))?;
+        Ok(word.to_le_bytes()[address % 2])
+    }
+
+    pub fn write_byte(&mut self, address: usize, value: u8) -> Result<(), VmErrorKind> {
+        let mut bytes = self
+            .read_memory(address / 2)
+            .map_err(|_| VmErrorKind::MemoryFault(address))?
+            .to_le_bytes();
+        bytes[address % 2] = value;
+        self.write_memory(address / 2, u16::from_le_bytes(bytes))
+    }
+
+    // Records a hit for every watchpoint that the access overlaps. The pc is
+    // filled in once the instruction completes.
+    fn note_access(&mut self, address: usize, length: usize, access: Access) {
+        for watchpoint in &self.watchpoints {
+            if watchpoint.matches_memory(address, length, access) {
+                self.watch_hits.push(WatchHit {
+                    pc: 0,
+                    access,
+                    watchpoint: *watchpoint,
+                    location: HitLocation::Memory { address, length },
+                });
+            }
+        }
+    }
+
+    // Video memory only changes inside `sys`, so video watchpoints compare
+    // their pixels before and after the call.
+    fn watched_pixels(&self) -> Vec<Vec<Color>> {
+        self.watchpoints
+            .iter()
+            .map(|watchpoint| {
+                self.video_region(watchpoint)
+                    .map(|index| self.video_memory[index])
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn note_video_writes(&mut self, before: &[Vec<Color>]) {
+        for (watchpoint, pixels) in self.watchpoints.iter().zip(before) {
+            let changed = self
+                .video_region(watchpoint)
+                .zip(pixels)
+                .find(|(index, old)| self.video_memory[*index] != **old);
+
+            if let Some((index, _)) = changed {
+                self.watch_hits.push(WatchHit {
+                    pc: 0,
+                    access: Access::Write,
+                    watchpoint: *watchpoint,
+                    location: HitLocation::Pixel {
+                        x: index % self.framebuffer_width,
+                        y: index / self.framebuffer_width,
+                    },
+                });
+            }
+        }
+    }
+
+    /// Sets the pixel at `index` in video memory. The drawing functions write
+    /// through here so the journal only holds the pixels they changed.
+    pub fn write_pixel(&mut self, index: usize, color: Color) {
+        let pixel = &mut self.video_memory[index];
+        if *pixel == color {
+            return;
+        }
+
+        if let Some(journal) = &mut self.journal {
+            journal.pixels.push((index, *pixel));
+        }
+
+        *pixel = color;
+    }
+
+    // Pixel indices of a video watchpoint, clipped to the framebuffer.
+    fn video_region(&self, watchpoint: &Watchpoint) -> impl Iterator<Item = usize> + use<> {
+        let (x, y, width, height) = match watchpoint.target {
+            WatchTarget::Video {
+                x,
+                y,
+                width,
+                height,
+            } => (x, y, width, height),
+            WatchTarget::Memory { .. } => (0, 0, 0, 0),
+        };
+
+        let stride = self.framebuffer_width;
+        let columns = x.min(stride)..(x + width).min(stride);
+        let rows = y.min(self.framebuffer_height)..(y + height).min(self.framebuffer_height);
+
+        rows.flat_map(move |row| columns.clone().map(move |column| row * stride + column))
+    }
+
+    // Words are little-endian and may start at an odd offset; the second byte
+    // wraps around within the data segment.
+    pub fn read_data_word(&self, offset: u16) -> Result<u16, VmErrorKind> {
+        let low = self.read_byte(self.data_address(offset))?;
+        let high = self.read_byte(self.data_address(offset.wrapping_add(1)))?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+
+    pub fn write_data_word(&mut self, offset: u16, value: u16) -> Result<(), VmErrorKind> {
+        let [low, high] = value.to_le_bytes();
+        let high_address = self.data_address(offset.wrapping_add(1));
+
+        // Fault before writing anything if the second byte is out of range.
+        self.read_byte(high_address)?;
+        self.write_byte(self.data_address(offset), low)?;
+        self.write_byte(high_address, high)
+    }
+
+    // The stack grows down from the top of the STACK_OFFSET region and `sp`
+    // counts words from the start of that region.
+    pub fn push_word(&mut self, value: u16) -> Result<(), VmErrorKind> {
+        self.sp = self.sp.checked_sub(1).ok_or(VmErrorKind::StackOverflow)?;
+        self.note_access((STACK_BASE + self.sp as usize) * 2, 2, Access::Write);
+        self.write_memory(STACK_BASE + self.sp as usize, value)
+    }
+
+    pub fn pop_word(&mut self) -> Result<u16, VmErrorKind> {
+        if self.sp >= STACK_WORDS {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+
+        self.note_access((STACK_BASE + self.sp as usize) * 2, 2, Access::Read);
+        let value = self.read_memory(STACK_BASE + self.sp as usize)?;
+        self.sp += 1;
+        Ok(value)
+    }
+
+    /// Delivers a key press or release from the host. `K0` holds the most
+    /// recently pressed key that is still down.
+    pub fn key_event(&mut self, keycode: u16, pressed: bool, repeat: bool) {
+        self.input(InputEvent::Key { keycode, pressed, repeat });
+    }
+
+    /// Delivers text typed on the host, as characters rather than keys.
+    pub fn text_input(&mut self, text: &str) {
+        self.input(InputEvent::Text(text.to_string()));
+    }
+
+    /// Delivers the pointer position from the host, in framebuffer pixels.
+    pub fn mouse_move(&mut self, x: u16, y: u16, inside: bool) {
+        self.input(InputEvent::MouseMove { x, y, inside });
+    }
+
+    /// Delivers a press or release of the mouse buttons in `button`.
+    pub fn mouse_button(&mut self, button: u16, pressed: bool) {
+        self.input(InputEvent::MouseButton { button, pressed });
+    }
+
+    /// Delivers wheel movement, in lines, positive away from the user.
+    pub fn mouse_scroll(&mut self, lines: i16) {
+        self.input(InputEvent::MouseScroll(lines));
+    }
+
+    /// Delivers an input event to the devices, recording it first if a
+    /// recording is running.
+    pub fn input(&mut self, event: InputEvent) {
+        if let Some(recorder) = &self.input_recorder
+            && let Err(e) = recorder.record(self.cycles, &event)
+        {
+            eprintln!("error recording input, recording stopped: {}", e);
+            self.input_recorder = None;
+        }
+
+        match event {
+            InputEvent::Key { keycode, pressed, repeat } => {
+                self.devices_mut().key_event(keycode, pressed, repeat);
+                if let Some(backend) = &mut self.gamepad_backend {
+                    backend.key_event(keycode, pressed);
+                }
+                self.registers[Register::index(Register::K0)] = self.devices.keyboard.held_key();
+            }
+            InputEvent::Text(text) => self.devices_mut().text_input(&text),
+            InputEvent::MouseMove { x, y, inside } => self.devices_mut().mouse_move(x, y, inside),
+            InputEvent::MouseButton { button, pressed } => {
+                self.devices_mut().mouse_button(button, pressed)
+            }
+            InputEvent::MouseScroll(lines) => self.devices_mut().mouse_scroll(lines),
+        }
+    }
+
+    /// Ends the input recording, if one is running, at the current cycle
+    /// count.
+    pub fn finish_input_recording(&mut self) {
+        if let Some(recorder) = self.input_recorder.take()
+            && let Err(e) = recorder.finish(self.cycles)
+        {
+            eprintln!("error recording input: {}", e);
+        }
+    }
+
+    /// Whether an input replay reached the end of its recorded session.
+    pub fn input_replay_finished(&self) -> bool {
+        self.input_replay
+            .as_ref()
+            .is_some_and(|replay| replay.finished(self.cycles))
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.memory.get(self.code_address(self.pc)) == Some(&(OpcodeType::Hlt as u16))
+    }
+
+    pub fn step(&mut self) -> Result<(), VmError> {
+        self.execute_instruction().map(|_| ())
+    }
+
+    pub fn read_memory_from_file(&mut self, file_path: impl AsRef<Path>) -> io::Result<usize> {
         let buffer = std::fs::read(file_path)?;
-        let word_count = buffer.len() / 2;
+        self.load_program(&buffer)
+    }
 
-        for (i, chunk) in buffer.chunks_exact(2).enumerate() {
-            self.memory[i] = u16::from_le_bytes([chunk[0], chunk[1]]);
+    // Programs are loaded at the start of the code region; the kernel region
+    // below it is left for the VM.
+    pub fn load_program(&mut self, program: &[u8]) -> io::Result<usize> {
+        if program.len() > CODE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "program is {} bytes but the code region holds {} bytes",
+                    program.len(),
+                    CODE_SIZE
+                ),
+            ));
         }
 
-        for i in word_count..self.memory.len() {
-            self.memory[i] = 0;
+        self.memory.fill(0);
+
+        let code_base = CODE_OFFSET / 2;
+        for (i, chunk) in program.chunks(2).enumerate() {
+            let high = chunk.get(1).copied().unwrap_or(0);
+            self.memory[code_base + i] = u16::from_le_bytes([chunk[0], high]);
         }
 
-        Ok(buffer.len())
+        self.registers[Register::index(Register::CS)] = CODE_SEGMENT;
+        self.registers[Register::index(Register::DS)] = CODE_SEGMENT;
+        self.pc = 0;
+        self.sp = STACK_WORDS;
+
+        Ok(program.len())
     }
 
-    pub fn read_iso(&mut self, iso_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn read_iso(&mut self, iso_path: impl AsRef<Path>) -> Result<usize, Box<dyn std::error::Error>> {
         let file = File::open(iso_path)?;
         let iso = ISO9660::new(file)?;
 
         let mut contents = Vec::new();
         if let Some(DirectoryEntry::File(file)) = iso.open("boot.bin")? {
             file.read().read_to_end(&mut contents)?;
-
-            let tmp_file_path = "boot.bin";
-            std::fs::write(tmp_file_path, &contents)?;
-
-            self.read_memory_from_file(tmp_file_path)?;
+            return Ok(self.load_program(&contents)?);
         }
 
-        Ok(())
+        Ok(0)
     }
 
 
@@ -600,6 +1235,20 @@ impl MicroCVMCpu {
     pub fn get_flag(&self, flag: u16) -> bool {
         self.flags & flag != 0
     }
+
+    // The flags as `ZCSO`, with `-` for each clear flag.
+    pub fn flag_names(&self) -> String {
+        [
+            (FLAG_ZERO, 'Z'),
+            (FLAG_CARRY, 'C'),
+            (FLAG_SIGN, 'S'),
+            (FLAG_OVERFLOW, 'O'),
+            (FLAG_INTERRUPT, 'I'),
+        ]
+        .iter()
+        .map(|&(flag, name)| if self.get_flag(flag) { name } else { '-' })
+        .collect()
+    }
 }
 
 impl Opcode {
@@ -611,10 +1260,32 @@ impl Opcode {
             arg2: None,
         }
     }
+
+    pub fn register(&self, argument: Option<OpcodeArgument>) -> Result<Register, VmErrorKind> {
+        match argument {
+            Some(OpcodeArgument::Register(reg)) => Ok(reg),
+            Some(OpcodeArgument::Immediate(value)) => Err(VmErrorKind::InvalidRegister(value)),
+            None => Err(VmErrorKind::BadOperand(self.opcode_type)),
+        }
+    }
+
+    pub fn destination(&self, argument: Option<OpcodeArgument>) -> Result<Register, VmErrorKind> {
+        match self.register(argument)? {
+            Register::CS => Err(VmErrorKind::BadOperand(self.opcode_type)),
+            reg => Ok(reg),
+        }
+    }
+
+    pub fn immediate(&self, argument: Option<OpcodeArgument>) -> Result<u16, VmErrorKind> {
+        match argument {
+            Some(OpcodeArgument::Immediate(imm)) => Ok(imm),
+            _ => Err(VmErrorKind::BadOperand(self.opcode_type)),
+        }
+    }
 }
 
 impl TryFrom<u16> for OpcodeType {
-    type Error = InvalidOpcode;
+    type Error = VmErrorKind;
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
@@ -643,7 +1314,25 @@ impl TryFrom<u16> for OpcodeType {
             0x15 => Ok(OpcodeType::Pop),
             0x16 => Ok(OpcodeType::Ret),
             0x17 => Ok(OpcodeType::Load8),
-            invalid => return Err(InvalidOpcode(invalid)),
+            0x18 => Ok(OpcodeType::Sys),
+            0x19 => Ok(OpcodeType::Jl),
+            0x1A => Ok(OpcodeType::Jg),
+            0x1B => Ok(OpcodeType::Jle),
+            0x1C => Ok(OpcodeType::Jge),
+            0x1D => Ok(OpcodeType::Jb),
+            0x1E => Ok(OpcodeType::Ja),
+            0x1F => Ok(OpcodeType::Jc),
+            0x20 => Ok(OpcodeType::Jo),
+            0x21 => Ok(OpcodeType::Pushf),
+            0x22 => Ok(OpcodeType::Popf),
+            0x23 => Ok(OpcodeType::Store8),
+            0x24 => Ok(OpcodeType::In),
+            0x25 => Ok(OpcodeType::Out),
+            0x26 => Ok(OpcodeType::Int),
+            0x27 => Ok(OpcodeType::Iret),
+            0x28 => Ok(OpcodeType::Cli),
+            0x29 => Ok(OpcodeType::Sti),
+            invalid => Err(VmErrorKind::InvalidOpcode(invalid)),
         }
     }
 }
@@ -678,13 +1367,31 @@ impl TryFrom<&str> for OpcodeType {
             "pop" => Ok(OpcodeType::Pop),
             "ret" => Ok(OpcodeType::Ret),
             "load8" => Ok(OpcodeType::Load8),
-            invalid => return Err(InvalidOpcodeString(invalid.to_string())),
+            "sys" => Ok(OpcodeType::Sys),
+            "jl" => Ok(OpcodeType::Jl),
+            "jg" => Ok(OpcodeType::Jg),
+            "jle" => Ok(OpcodeType::Jle),
+            "jge" => Ok(OpcodeType::Jge),
+            "jb" => Ok(OpcodeType::Jb),
+            "ja" => Ok(OpcodeType::Ja),
+            "jc" => Ok(OpcodeType::Jc),
+            "jo" => Ok(OpcodeType::Jo),
+            "pushf" => Ok(OpcodeType::Pushf),
+            "popf" => Ok(OpcodeType::Popf),
+            "store8" => Ok(OpcodeType::Store8),
+            "in" => Ok(OpcodeType::In),
+            "out" => Ok(OpcodeType::Out),
+            "int" => Ok(OpcodeType::Int),
+            "iret" => Ok(OpcodeType::Iret),
+            "cli" => Ok(OpcodeType::Cli),
+            "sti" => Ok(OpcodeType::Sti),
+            invalid => Err(InvalidOpcodeString(invalid.to_string())),
         }
     }
 }
 
 impl TryFrom<u16> for Register {
-    type Error = InvalidRegister;
+    type Error = VmErrorKind;
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
@@ -715,8 +1422,11 @@ impl TryFrom<u16> for Register {
             0x2010 => Ok(Register::V15),
             //Keycodes
             0x3001 => Ok(Register::K0),
+            //Segment registers
+            0x4001 => Ok(Register::CS),
+            0x4002 => Ok(Register::DS),
 
-            _ => Err(InvalidRegister(value)),
+            _ => Err(VmErrorKind::InvalidRegister(value)),
         }
     }
 }
@@ -753,13 +1463,16 @@ impl TryFrom<&str> for Register {
             "v15" => Ok(Register::V15),
             //Keycodes
             "k0" => Ok(Register::K0),
+            //Segment registers
+            "cs" => Ok(Register::CS),
+            "ds" => Ok(Register::DS),
             invalid => Err(InvalidRegisterString(invalid.to_string())),
         }
     }
 }
 
 impl TryFrom<u16> for FunctionCall {
-    type Error = InvalidFunctionCall;
+    type Error = VmErrorKind;
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
@@ -771,7 +1484,7 @@ impl TryFrom<u16> for FunctionCall {
             0x18 => Ok(FunctionCall::FillRect),
             0x19 => Ok(FunctionCall::FillQuad),
             0x1A => Ok(FunctionCall::DrawCharacter),
-            invalid => return Err(InvalidFunctionCall(invalid)),
+            invalid => Err(VmErrorKind::InvalidFunctionCall(invalid)),
         }
     }
 }
@@ -786,6 +1499,7 @@ impl TryFrom<&str> for FunctionCall {
             "fill_screen" => Ok(FunctionCall::FillScreen),
             "clear_screen" => Ok(FunctionCall::ClearScreen),
             "load_bmp" => Ok(FunctionCall::LoadBMP),
+            "fill_rect" => Ok(FunctionCall::FillRect),
             "fill_quad" => Ok(FunctionCall::FillQuad),
             "draw_character" => Ok(FunctionCall::DrawCharacter),
             invalid => Err(InvalidFunctionCallString(invalid.to_string())),
@@ -793,7 +1507,139 @@ impl TryFrom<&str> for FunctionCall {
     }
 }
 
+impl Display for OpcodeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            OpcodeType::Load => "load",
+            OpcodeType::Store => "store",
+            OpcodeType::Add => "add",
+            OpcodeType::Sub => "sub",
+            OpcodeType::Jmp => "jmp",
+            OpcodeType::Hlt => "hlt",
+            OpcodeType::Mov => "mov",
+            OpcodeType::Inc => "inc",
+            OpcodeType::Div => "div",
+            OpcodeType::Mul => "mul",
+            OpcodeType::Nop => "nop",
+            OpcodeType::Call => "call",
+            OpcodeType::Je => "je",
+            OpcodeType::Jne => "jne",
+            OpcodeType::Cmp => "cmp",
+            OpcodeType::And => "and",
+            OpcodeType::Or => "or",
+            OpcodeType::Xor => "xor",
+            OpcodeType::Not => "not",
+            OpcodeType::Shl => "shl",
+            OpcodeType::Shr => "shr",
+            OpcodeType::Push => "push",
+            OpcodeType::Pop => "pop",
+            OpcodeType::Ret => "ret",
+            OpcodeType::Load8 => "load8",
+            OpcodeType::Sys => "sys",
+            OpcodeType::Jl => "jl",
+            OpcodeType::Jg => "jg",
+            OpcodeType::Jle => "jle",
+            OpcodeType::Jge => "jge",
+            OpcodeType::Jb => "jb",
+            OpcodeType::Ja => "ja",
+            OpcodeType::Jc => "jc",
+            OpcodeType::Jo => "jo",
+            OpcodeType::Pushf => "pushf",
+            OpcodeType::Popf => "popf",
+            OpcodeType::Store8 => "store8",
+            OpcodeType::In => "in",
+            OpcodeType::Out => "out",
+            OpcodeType::Int => "int",
+            OpcodeType::Iret => "iret",
+            OpcodeType::Cli => "cli",
+            OpcodeType::Sti => "sti",
+        };
+        f.pad(name)
+    }
+}
+
+impl Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Register::R0 => "r0",
+            Register::R1 => "r1",
+            Register::R2 => "r2",
+            Register::R3 => "r3",
+            Register::R4 => "r4",
+            Register::R5 => "r5",
+            Register::R6 => "r6",
+            Register::R7 => "r7",
+            Register::V0 => "v0",
+            Register::V1 => "v1",
+            Register::V2 => "v2",
+            Register::V3 => "v3",
+            Register::V4 => "v4",
+            Register::V5 => "v5",
+            Register::V6 => "v6",
+            Register::V7 => "v7",
+            Register::V8 => "v8",
+            Register::V9 => "v9",
+            Register::V10 => "v10",
+            Register::V11 => "v11",
+            Register::V12 => "v12",
+            Register::V13 => "v13",
+            Register::V14 => "v14",
+            Register::V15 => "v15",
+            Register::K0 => "k0",
+            Register::CS => "cs",
+            Register::DS => "ds",
+        };
+        f.pad(name)
+    }
+}
+
+impl Display for FunctionCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FunctionCall::SetPixel => "set_pixel",
+            FunctionCall::DrawLine => "draw_line",
+            FunctionCall::FillScreen => "fill_screen",
+            FunctionCall::ClearScreen => "clear_screen",
+            FunctionCall::LoadBMP => "load_bmp",
+            FunctionCall::FillRect => "fill_rect",
+            FunctionCall::FillQuad => "fill_quad",
+            FunctionCall::DrawCharacter => "draw_character",
+        };
+        f.pad(name)
+    }
+}
+
 impl Register {
+    pub const ALL: [Register; REGISTER_COUNT] = [
+        Register::R0,
+        Register::R1,
+        Register::R2,
+        Register::R3,
+        Register::R4,
+        Register::R5,
+        Register::R6,
+        Register::R7,
+        Register::V0,
+        Register::V1,
+        Register::V2,
+        Register::V3,
+        Register::V4,
+        Register::V5,
+        Register::V6,
+        Register::V7,
+        Register::V8,
+        Register::V9,
+        Register::V10,
+        Register::V11,
+        Register::V12,
+        Register::V13,
+        Register::V14,
+        Register::V15,
+        Register::K0,
+        Register::CS,
+        Register::DS,
+    ];
+
     pub fn index(self) -> usize {
         match self {
             Register::R0 => 0,
@@ -823,6 +1669,201 @@ impl Register {
             Register::V15 => 24,
             //Keycodes
             Register::K0 => 16,
+            //Segment registers
+            Register::CS => 25,
+            Register::DS => 26,
         }
     }
 }
+
+// Shared fixture for the tests of every module that needs a running machine.
+#[cfg(test)]
+impl MicroCVMCpu {
+    pub fn from_source(source: &str) -> Self {
+        let program = crate::asm::assemble(source).unwrap();
+        let mut cpu = MicroCVMCpu::new(MIN_MEMORY_SIZE, 32, 16);
+        cpu.load_program(&program).unwrap();
+        cpu
+    }
+
+    pub fn run_to_halt(&mut self) {
+        for _ in 0..10_000 {
+            if self.is_halted() {
+                return;
+            }
+            self.step().unwrap();
+        }
+
+        panic!("program did not halt");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{IRQ_BASE, IRQ_KEYBOARD, IRQ_MOUSE, IRQ_TIMER};
+
+    // The arithmetic flags as `ZCSO`, with `-` for each clear flag.
+    fn flags(cpu: &MicroCVMCpu) -> String {
+        [(FLAG_ZERO, 'Z'), (FLAG_CARRY, 'C'), (FLAG_SIGN, 'S'), (FLAG_OVERFLOW, 'O')]
+            .iter()
+            .map(|&(flag, name)| if cpu.get_flag(flag) { name } else { '-' })
+            .collect()
+    }
+
+    #[test]
+    fn add_sets_carry_and_overflow() {
+        let mut cpu = MicroCVMCpu::new(MIN_MEMORY_SIZE, 0, 0);
+
+        assert_eq!(cpu.add_with_flags(0xFFFF, 1), 0);
+        assert_eq!(flags(&cpu), "ZC--");
+
+        assert_eq!(cpu.add_with_flags(0x7FFF, 1), 0x8000);
+        assert_eq!(flags(&cpu), "--SO");
+
+        assert_eq!(cpu.add_with_flags(0x8000, 0x8000), 0);
+        assert_eq!(flags(&cpu), "ZC-O");
+    }
+
+    #[test]
+    fn sub_sets_borrow_and_overflow() {
+        let mut cpu = MicroCVMCpu::new(MIN_MEMORY_SIZE, 0, 0);
+
+        assert_eq!(cpu.sub_with_flags(0, 1), 0xFFFF);
+        assert_eq!(flags(&cpu), "-CS-");
+
+        assert_eq!(cpu.sub_with_flags(0x8000, 1), 0x7FFF);
+        assert_eq!(flags(&cpu), "---O");
+
+        assert_eq!(cpu.sub_with_flags(5, 5), 0);
+        assert_eq!(flags(&cpu), "Z---");
+    }
+
+    #[test]
+    fn shifts_carry_the_last_bit_out() {
+        let mut cpu = MicroCVMCpu::new(MIN_MEMORY_SIZE, 0, 0);
+
+        assert_eq!(cpu.shift_with_flags(0xC000, 1, true), 0x8000);
+        assert_eq!(flags(&cpu), "-CS-");
+
+        assert_eq!(cpu.shift_with_flags(0x4000, 1, true), 0x8000);
+        assert_eq!(flags(&cpu), "--SO");
+
+        assert_eq!(cpu.shift_with_flags(0x0003, 1, false), 0x0001);
+        assert_eq!(flags(&cpu), "-C--");
+
+        assert_eq!(cpu.shift_with_flags(0x8000, 16, false), 0);
+        assert_eq!(flags(&cpu), "ZC-O");
+
+        assert_eq!(cpu.shift_with_flags(0xFFFF, 17, true), 0);
+        assert_eq!(flags(&cpu), "Z--O");
+
+        assert_eq!(cpu.shift_with_flags(0x1234, 0, true), 0x1234);
+        assert_eq!(flags(&cpu), "----");
+    }
+
+    #[test]
+    fn conditional_jumps_follow_cmp() {
+        // Signed comparisons see 0xFFFF as -1 and 0x8000 as -32768; unsigned
+        // ones see them as large numbers.
+        let cases = [
+            (1, 2, OpcodeType::Jl, true),
+            (1, 2, OpcodeType::Jb, true),
+            (1, 2, OpcodeType::Jg, false),
+            (1, 2, OpcodeType::Ja, false),
+            (0xFFFF, 1, OpcodeType::Jl, true),
+            (0xFFFF, 1, OpcodeType::Jb, false),
+            (0xFFFF, 1, OpcodeType::Ja, true),
+            (0xFFFF, 1, OpcodeType::Jc, false),
+            (0x8000, 1, OpcodeType::Jo, true),
+            (0x8000, 1, OpcodeType::Jl, true),
+            (0x8000, 1, OpcodeType::Jge, false),
+            (2, 1, OpcodeType::Jo, false),
+            (2, 1, OpcodeType::Jg, true),
+            (2, 1, OpcodeType::Jge, true),
+            (5, 5, OpcodeType::Je, true),
+            (5, 5, OpcodeType::Jne, false),
+            (5, 5, OpcodeType::Jle, true),
+            (5, 5, OpcodeType::Jge, true),
+            (5, 5, OpcodeType::Jg, false),
+            (5, 5, OpcodeType::Ja, false),
+        ];
+
+        let mut cpu = MicroCVMCpu::new(MIN_MEMORY_SIZE, 0, 0);
+        for (lhs, rhs, jump, taken) in cases {
+            // `cmp` sets the flags of a subtraction and discards the result.
+            cpu.sub_with_flags(lhs, rhs);
+            assert_eq!(
+                cpu.condition_met(jump),
+                taken,
+                "{:?} after cmp {:#06X}, {:#06X}",
+                jump,
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    // Points hardware interrupt `vector` at the code offset `handler`.
+    fn install_handler(cpu: &mut MicroCVMCpu, vector: u8, handler: u16) {
+        let entry = IVT_OFFSET / 2 + vector as usize * 2;
+        cpu.memory[entry] = handler;
+        cpu.memory[entry + 1] = CODE_SEGMENT;
+    }
+
+    #[test]
+    fn entering_an_interrupt_is_a_step_of_its_own() {
+        let mut cpu = MicroCVMCpu::from_source("sti\nnop\nhlt\nhandler: mov r0, 1\niret\n");
+        install_handler(&mut cpu, IRQ_BASE + IRQ_KEYBOARD, 3);
+        cpu.step().unwrap();
+        cpu.devices.interrupts.raise(IRQ_KEYBOARD);
+
+        assert_eq!(
+            cpu.execute_instruction(),
+            Ok(Executed::Interrupt(IRQ_BASE + IRQ_KEYBOARD))
+        );
+        assert_eq!((cpu.pc, cpu.registers[Register::R0.index()]), (3, 0));
+
+        assert_eq!(cpu.execute_instruction(), Ok(Executed::Instruction));
+        assert_eq!(cpu.registers[Register::R0.index()], 1);
+
+        cpu.run_to_halt();
+        assert_eq!(cpu.pc, 2);
+    }
+
+    #[test]
+    fn undeliverable_interrupts_stay_pending() {
+        let mut cpu = MicroCVMCpu::from_source("sti\nnop\nnop\nhlt\nhandler: iret\n");
+        install_handler(&mut cpu, IRQ_BASE + IRQ_MOUSE, 4);
+        cpu.step().unwrap();
+
+        // The timer has no handler; the mouse behind it is still delivered.
+        cpu.devices.interrupts.raise(IRQ_TIMER);
+        cpu.devices.interrupts.raise(IRQ_MOUSE);
+        assert_eq!(
+            cpu.execute_instruction(),
+            Ok(Executed::Interrupt(IRQ_BASE + IRQ_MOUSE))
+        );
+        assert_eq!(cpu.devices.interrupts.pending, 1 << IRQ_TIMER);
+
+        // With no room on the stack entering the handler faults, and the
+        // request waits for the next attempt.
+        let mut cpu = MicroCVMCpu::from_source("sti\nnop\nhlt\nhandler: iret\n");
+        install_handler(&mut cpu, IRQ_BASE + IRQ_MOUSE, 3);
+        cpu.step().unwrap();
+        cpu.sp = 2;
+        cpu.devices.interrupts.raise(IRQ_MOUSE);
+        assert_eq!(
+            cpu.execute_instruction(),
+            Err(VmError::new(1, VmErrorKind::StackOverflow))
+        );
+        assert_eq!(cpu.devices.interrupts.pending, 1 << IRQ_MOUSE);
+
+        cpu.sp = STACK_WORDS;
+        assert_eq!(
+            cpu.execute_instruction(),
+            Ok(Executed::Interrupt(IRQ_BASE + IRQ_MOUSE))
+        );
+        assert_eq!(cpu.devices.interrupts.pending, 0);
+    }
+}
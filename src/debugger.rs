@@ -0,0 +1,614 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::sync::Mutex;
+
+use crate::asm::parse_number;
+use crate::cpu::{MicroCVMCpu, OpcodeType, Register, VmError};
+use crate::disasm;
+use crate::history::History;
+use crate::mouse;
+use crate::trace::Tracer;
+use crate::watch::{WatchHit, WatchKind, Watchpoint};
+
+const HELP: &str = "\
+Addresses are code offsets (as shown by the disassembly) for breakpoints and
+data segment byte offsets for memory. Numbers are decimal, 0x hex or 0b binary.
+
+  s, step [COUNT]          execute COUNT instructions (default 1)
+  n, next                  step over a call
+  c, continue              run until a breakpoint, hlt or a fault
+  rs, reverse-step [COUNT] undo COUNT instructions (default 1)
+  rc, reverse-continue [ADDR]
+                           run backwards to a breakpoint, or to the last
+                           instruction that wrote memory at ADDR
+  b, break ADDR            set a breakpoint
+  d, delete ADDR           remove a breakpoint
+  bl, breakpoints          list breakpoints
+  r, regs                  show registers and flags
+  dis [ADDR] [COUNT]       disassemble COUNT instructions (default: at pc)
+  x ADDR [COUNT]           dump COUNT bytes of memory (default 64)
+  watch [r|w|rw] ADDR [LEN]
+                           stop when memory is read and/or written
+  watch video X Y W H      stop when a framebuffer rectangle changes
+  unwatch N                remove watchpoint N
+  watches                  list watchpoints
+  set REG VALUE            set a register, pc, sp or flags
+  poke ADDR BYTE...        write bytes to memory
+  key CODE [up|repeat]     press, release or auto-repeat a key
+  type TEXT                send TEXT to the text input queue
+  mouse X Y                move the pointer to a framebuffer pixel
+  mouse down|up BUTTON     press or release left, right, middle, back or forward
+  mouse wheel LINES        scroll the wheel, positive away from the user
+  h, help                  show this help
+  q, quit                  leave the debugger
+  <empty line>             repeat the last command";
+
+enum Stop {
+    Stepped,
+    Breakpoint,
+    Watchpoint(Vec<WatchHit>),
+    Halted,
+    Fault(VmError),
+    LastWrite(u16),
+    HistoryStart,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    last_command: String,
+    tracer: Option<Tracer>,
+    history: History,
+}
+
+impl Debugger {
+    pub fn new(tracer: Option<Tracer>) -> Self {
+        Self {
+            tracer,
+            ..Self::default()
+        }
+    }
+
+    /// Runs the command prompt until `quit` or end of input. The CPU lock is
+    /// only held for one instruction at a time so the window keeps drawing
+    /// while the guest runs.
+    pub fn run(
+        &mut self,
+        cpu: &Mutex<MicroCVMCpu>,
+        mut input: impl BufRead,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        self.show_stop(cpu, &mut output)?;
+
+        loop {
+            write!(output, "(mdb) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return self.finish();
+            }
+
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            self.last_command = line.clone();
+
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let Some((&command, arguments)) = words.split_first() else {
+                continue;
+            };
+
+            match command {
+                "q" | "quit" => return self.finish(),
+                "h" | "help" => writeln!(output, "{}", HELP)?,
+                "s" | "step" => {
+                    let count = match arguments.first() {
+                        Some(count) => match parse_number(count) {
+                            Some(count) => count,
+                            None => {
+                                writeln!(output, "invalid count `{}`", count)?;
+                                continue;
+                            }
+                        },
+                        None => 1,
+                    };
+
+                    let mut stop = Stop::Stepped;
+                    for _ in 0..count {
+                        stop = self.step(cpu);
+                        if !matches!(stop, Stop::Stepped) {
+                            break;
+                        }
+                    }
+                    self.report(stop, cpu, &mut output)?;
+                }
+                "n" | "next" => {
+                    let stop = self.step_over(cpu);
+                    self.report(stop, cpu, &mut output)?;
+                }
+                "c" | "continue" => {
+                    let stop = self.resume(cpu, |_| false);
+                    self.report(stop, cpu, &mut output)?;
+                }
+                "rs" | "reverse-step" => {
+                    let count = match arguments.first() {
+                        Some(count) => match parse_number(count) {
+                            Some(count) => count,
+                            None => {
+                                writeln!(output, "invalid count `{}`", count)?;
+                                continue;
+                            }
+                        },
+                        None => 1,
+                    };
+
+                    self.end_input_recording(cpu, &mut output)?;
+                    let mut stop = Stop::Stepped;
+                    for _ in 0..count {
+                        if self.history.step_back(&mut cpu.lock().unwrap(), None).is_none() {
+                            stop = Stop::HistoryStart;
+                            break;
+                        }
+                    }
+                    self.report(stop, cpu, &mut output)?;
+                }
+                "rc" | "reverse-continue" => {
+                    let offset = match arguments.first() {
+                        Some(offset) => match parse_number(offset) {
+                            Some(offset) => Some(offset),
+                            None => {
+                                writeln!(output, "invalid address `{}`", offset)?;
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    self.end_input_recording(cpu, &mut output)?;
+                    let stop = self.reverse(cpu, offset);
+                    self.report(stop, cpu, &mut output)?;
+                }
+                "b" | "break" => match arguments.first().and_then(|a| parse_number(a)) {
+                    Some(address) => {
+                        self.breakpoints.insert(address);
+                        writeln!(output, "breakpoint at {:04X}", address)?;
+                    }
+                    None => writeln!(output, "usage: break ADDR")?,
+                },
+                "d" | "delete" => match arguments.first().and_then(|a| parse_number(a)) {
+                    Some(address) if self.breakpoints.remove(&address) => {
+                        writeln!(output, "deleted breakpoint at {:04X}", address)?
+                    }
+                    Some(address) => writeln!(output, "no breakpoint at {:04X}", address)?,
+                    None => writeln!(output, "usage: delete ADDR")?,
+                },
+                "bl" | "breakpoints" => {
+                    if self.breakpoints.is_empty() {
+                        writeln!(output, "no breakpoints")?;
+                    }
+                    for address in &self.breakpoints {
+                        writeln!(output, "  {:04X}", address)?;
+                    }
+                }
+                "r" | "regs" => write_registers(&cpu.lock().unwrap(), &mut output)?,
+                "dis" => {
+                    let cpu = cpu.lock().unwrap();
+                    let address = arguments.first().and_then(|a| parse_number(a));
+                    let count = arguments.get(1).and_then(|a| parse_number(a));
+                    write_disassembly(
+                        &cpu,
+                        address.unwrap_or(cpu.pc),
+                        count.unwrap_or(8),
+                        &mut output,
+                    )?;
+                }
+                "x" => match arguments.first().and_then(|a| parse_number(a)) {
+                    Some(offset) => {
+                        let count = arguments.get(1).and_then(|a| parse_number(a));
+                        write_memory(&cpu.lock().unwrap(), offset, count.unwrap_or(64), &mut output)?;
+                    }
+                    None => writeln!(output, "usage: x ADDR [COUNT]")?,
+                },
+                "watch" => {
+                    let mut cpu = cpu.lock().unwrap();
+                    match parse_watchpoint(&cpu, arguments) {
+                        Some(watchpoint) => {
+                            writeln!(output, "watching {}", watchpoint)?;
+                            cpu.watchpoints.push(watchpoint);
+                        }
+                        None => writeln!(
+                            output,
+                            "usage: watch [r|w|rw] ADDR [LEN] or watch video X Y W H"
+                        )?,
+                    }
+                }
+                "unwatch" => {
+                    let mut cpu = cpu.lock().unwrap();
+                    match arguments.first().and_then(|a| parse_number(a)) {
+                        Some(number) if (number as usize) < cpu.watchpoints.len() => {
+                            let watchpoint = cpu.watchpoints.remove(number as usize);
+                            writeln!(output, "removed {}", watchpoint)?;
+                        }
+                        _ => writeln!(output, "usage: unwatch N, see `watches`")?,
+                    }
+                }
+                "watches" => {
+                    let cpu = cpu.lock().unwrap();
+                    if cpu.watchpoints.is_empty() {
+                        writeln!(output, "no watchpoints")?;
+                    }
+                    for (number, watchpoint) in cpu.watchpoints.iter().enumerate() {
+                        writeln!(output, "  {}: {}", number, watchpoint)?;
+                    }
+                }
+                "set" => match (arguments.first(), arguments.get(1).and_then(|a| parse_number(a))) {
+                    (Some(target), Some(value)) => {
+                        self.history.forget_checkpoints();
+                        if let Err(e) = set_register(&mut cpu.lock().unwrap(), target, value) {
+                            writeln!(output, "{}", e)?;
+                        }
+                    }
+                    _ => writeln!(output, "usage: set REG VALUE")?,
+                },
+                "poke" => {
+                    let offset = arguments.first().and_then(|a| parse_number(a));
+                    let bytes = arguments[1.min(arguments.len())..]
+                        .iter()
+                        .map(|a| parse_number(a).filter(|b| *b <= 0xFF))
+                        .collect::<Option<Vec<_>>>();
+
+                    match (offset, bytes) {
+                        (Some(offset), Some(bytes)) if !bytes.is_empty() => {
+                            self.history.forget_checkpoints();
+                            let mut cpu = cpu.lock().unwrap();
+                            for (i, byte) in bytes.into_iter().enumerate() {
+                                let address = cpu.data_address(offset.wrapping_add(i as u16));
+                                if let Err(e) = cpu.write_byte(address, byte as u8) {
+                                    writeln!(output, "{}", e)?;
+                                    break;
+                                }
+                            }
+                        }
+                        _ => writeln!(output, "usage: poke ADDR BYTE...")?,
+                    }
+                }
+                "key" => {
+                    let keycode = arguments.first().and_then(|a| parse_number(a));
+                    let action = match arguments.get(1) {
+                        None => Some((true, false)),
+                        Some(&"up") => Some((false, false)),
+                        Some(&"repeat") => Some((true, true)),
+                        Some(_) => None,
+                    };
+
+                    match (keycode, action) {
+                        (Some(keycode), Some((pressed, repeat))) if keycode <= 0xFF => {
+                            self.history.forget_checkpoints();
+                            cpu.lock().unwrap().key_event(keycode, pressed, repeat);
+                        }
+                        _ => writeln!(output, "usage: key CODE [up|repeat]")?,
+                    }
+                }
+                "type" if !arguments.is_empty() => {
+                    self.history.forget_checkpoints();
+                    cpu.lock().unwrap().text_input(&arguments.join(" "));
+                }
+                "type" => writeln!(output, "usage: type TEXT")?,
+                "mouse" => match parse_mouse_action(arguments) {
+                    Some(action) => {
+                        self.history.forget_checkpoints();
+                        let mut cpu = cpu.lock().unwrap();
+                        match action {
+                            MouseAction::Move(x, y) => cpu.mouse_move(x, y, true),
+                            MouseAction::Button(button, pressed) => cpu.mouse_button(button, pressed),
+                            MouseAction::Scroll(lines) => cpu.mouse_scroll(lines),
+                        }
+                    }
+                    None => writeln!(
+                        output,
+                        "usage: mouse X Y | mouse down|up BUTTON | mouse wheel LINES"
+                    )?,
+                },
+                _ => writeln!(output, "unknown command `{}`, try `help`", command)?,
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn step(&mut self, cpu: &Mutex<MicroCVMCpu>) -> Stop {
+        let mut cpu = cpu.lock().unwrap();
+
+        if cpu.is_halted() {
+            return Stop::Halted;
+        }
+
+        match self.history.step(&mut cpu, self.tracer.as_mut()) {
+            Ok(()) if !cpu.watch_hits.is_empty() => {
+                Stop::Watchpoint(cpu.watch_hits.drain(..).collect())
+            }
+            Ok(()) => Stop::Stepped,
+            Err(e) => Stop::Fault(e),
+        }
+    }
+
+    // Runs a whole subroutine when the current instruction is a `call`: the
+    // stop is the return address with the stack back where it started.
+    fn step_over(&mut self, cpu: &Mutex<MicroCVMCpu>) -> Stop {
+        let (opcode, pc, sp) = {
+            let cpu = cpu.lock().unwrap();
+            (cpu.create_opcode(), cpu.pc, cpu.sp)
+        };
+
+        match opcode {
+            Ok(opcode) if opcode.opcode_type == OpcodeType::Call => {
+                let return_address = pc.wrapping_add(opcode.argument_count + 1);
+                self.resume(cpu, |cpu| cpu.pc == return_address && cpu.sp == sp)
+            }
+            _ => self.step(cpu),
+        }
+    }
+
+    // Always executes at least one instruction, so continuing from a
+    // breakpoint does not stop on it again straight away.
+    fn resume(&mut self, cpu: &Mutex<MicroCVMCpu>, until: impl Fn(&MicroCVMCpu) -> bool) -> Stop {
+        loop {
+            let stop = self.step(cpu);
+            if !matches!(stop, Stop::Stepped) {
+                return stop;
+            }
+
+            let cpu = cpu.lock().unwrap();
+            if until(&cpu) {
+                return Stop::Stepped;
+            }
+            if cpu.is_halted() {
+                return Stop::Halted;
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+    // Undoes at least one instruction, then stops before the instruction
+    // that last wrote data segment byte `offset`, or on a breakpoint when no
+    // offset is given.
+    fn reverse(&mut self, cpu: &Mutex<MicroCVMCpu>, offset: Option<u16>) -> Stop {
+        loop {
+            let mut cpu = cpu.lock().unwrap();
+            let address = offset.map(|offset| cpu.data_address(offset));
+
+            match self.history.step_back(&mut cpu, address) {
+                None => return Stop::HistoryStart,
+                Some(true) => return Stop::LastWrite(offset.unwrap_or_default()),
+                Some(false) if offset.is_none() && self.breakpoints.contains(&cpu.pc) => {
+                    return Stop::Breakpoint;
+                }
+                Some(false) => {}
+            }
+        }
+    }
+
+    // A recording is a single forward run, so going back in time ends it.
+    fn end_input_recording(&self, cpu: &Mutex<MicroCVMCpu>, output: &mut impl Write) -> io::Result<()> {
+        let mut cpu = cpu.lock().unwrap();
+        if cpu.input_recorder.is_some() {
+            cpu.finish_input_recording();
+            writeln!(output, "input recording ended before rewinding")?;
+        }
+
+        Ok(())
+    }
+
+    fn report(&self, stop: Stop, cpu: &Mutex<MicroCVMCpu>, output: &mut impl Write) -> io::Result<()> {
+        match stop {
+            Stop::Stepped => {}
+            Stop::Breakpoint => writeln!(output, "breakpoint hit")?,
+            Stop::Watchpoint(hits) => {
+                for hit in hits {
+                    writeln!(output, "watchpoint: {}", hit)?;
+                }
+            }
+            Stop::Halted => writeln!(output, "program halted")?,
+            Stop::Fault(e) => writeln!(output, "guest fault: {}", e)?,
+            Stop::LastWrite(offset) => writeln!(output, "last write to {:04X}", offset)?,
+            Stop::HistoryStart => writeln!(output, "reached the start of the recorded history")?,
+        }
+
+        self.show_stop(cpu, output)
+    }
+
+    fn show_stop(&self, cpu: &Mutex<MicroCVMCpu>, output: &mut impl Write) -> io::Result<()> {
+        let cpu = cpu.lock().unwrap();
+        write_registers(&cpu, output)?;
+
+        let before = preceding_instructions(&cpu, 3);
+        let start = before.front().copied().unwrap_or(cpu.pc);
+        write_disassembly(&cpu, start, before.len() as u16 + 5, output)
+    }
+}
+
+// Addresses of up to `count` instructions right before pc. Instructions vary
+// in length, so they are found by decoding from the start of the code segment
+// the way the disassembler does; if that never lands on pc (pc is inside what
+// decodes as data or operands) there is no reliable listing before it.
+fn preceding_instructions(cpu: &MicroCVMCpu, count: usize) -> VecDeque<u16> {
+    let mut addresses = VecDeque::with_capacity(count + 1);
+    let mut address = 0;
+
+    while address < cpu.pc {
+        addresses.push_back(address);
+        if addresses.len() > count {
+            addresses.pop_front();
+        }
+        address = address.saturating_add(disasm::decode(cpu, address, usize::MAX).len());
+    }
+
+    if address != cpu.pc {
+        addresses.clear();
+    }
+    addresses
+}
+
+fn write_registers(cpu: &MicroCVMCpu, output: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        output,
+        "pc={:04X} sp={:04X} flags={:04X} [{}] cycles={}",
+        cpu.pc,
+        cpu.sp,
+        cpu.flags,
+        cpu.flag_names(),
+        cpu.cycles
+    )?;
+
+    for row in Register::ALL.chunks(8) {
+        let row = row
+            .iter()
+            .map(|&register| format!("{:>3}={:04X}", register, cpu.registers[register.index()]))
+            .collect::<Vec<_>>();
+        writeln!(output, "{}", row.join(" "))?;
+    }
+
+    Ok(())
+}
+
+fn write_disassembly(
+    cpu: &MicroCVMCpu,
+    mut address: u16,
+    count: u16,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let labels = Default::default();
+
+    for _ in 0..count {
+        let instruction = disasm::decode(cpu, address, usize::MAX);
+        let marker = if address == cpu.pc { "=>" } else { "  " };
+        let words = instruction
+            .words
+            .iter()
+            .map(|word| format!("{:04X}", word))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        writeln!(
+            output,
+            "{} {:04X}:  {:<14}    {}",
+            marker,
+            address,
+            words,
+            disasm::format_instruction(&instruction, &labels)
+        )?;
+
+        address = address.wrapping_add(instruction.len());
+    }
+
+    Ok(())
+}
+
+fn write_memory(cpu: &MicroCVMCpu, offset: u16, count: u16, output: &mut impl Write) -> io::Result<()> {
+    for row_start in (0..count).step_by(16) {
+        let row_offset = offset.wrapping_add(row_start);
+        let bytes = (0..16.min(count - row_start))
+            .map(|i| cpu.read_byte(cpu.data_address(row_offset.wrapping_add(i))))
+            .collect::<Result<Vec<_>, _>>();
+
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(e) => return writeln!(output, "{}", e),
+        };
+
+        let hex = bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = bytes
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect::<String>();
+
+        writeln!(output, "{:04X}:  {:<47}  {}", row_offset, hex, text)?;
+    }
+
+    Ok(())
+}
+
+fn set_register(cpu: &mut MicroCVMCpu, target: &str, value: u16) -> Result<(), String> {
+    match target.to_ascii_lowercase().as_str() {
+        "pc" => cpu.pc = value,
+        "sp" => cpu.sp = value,
+        "flags" => cpu.flags = value,
+        name => {
+            let register = Register::try_from(name).map_err(|e| e.to_string())?;
+            cpu.registers[register.index()] = value;
+        }
+    }
+
+    Ok(())
+}
+
+enum MouseAction {
+    Move(u16, u16),
+    Button(u16, bool),
+    Scroll(i16),
+}
+
+fn parse_mouse_action(arguments: &[&str]) -> Option<MouseAction> {
+    match arguments {
+        [action @ ("down" | "up"), button] => {
+            let button = match *button {
+                "left" => mouse::BUTTON_LEFT,
+                "right" => mouse::BUTTON_RIGHT,
+                "middle" => mouse::BUTTON_MIDDLE,
+                "back" => mouse::BUTTON_BACK,
+                "forward" => mouse::BUTTON_FORWARD,
+                _ => return None,
+            };
+            Some(MouseAction::Button(button, *action == "down"))
+        }
+        ["wheel", lines] => lines.parse().ok().map(MouseAction::Scroll),
+        [x, y] => Some(MouseAction::Move(parse_number(x)?, parse_number(y)?)),
+        _ => None,
+    }
+}
+
+// Memory watchpoints take a data segment byte offset, like `x` and `poke`.
+fn parse_watchpoint(cpu: &MicroCVMCpu, arguments: &[&str]) -> Option<Watchpoint> {
+    let numbers = |arguments: &[&str]| {
+        arguments
+            .iter()
+            .map(|a| parse_number(a).map(usize::from))
+            .collect::<Option<Vec<_>>>()
+    };
+
+    let (kind, arguments) = match arguments.first() {
+        Some(&"video") => {
+            return match numbers(&arguments[1..])?.as_slice() {
+                [x, y, width, height] => Some(Watchpoint::video(*x, *y, *width, *height)),
+                _ => None,
+            };
+        }
+        Some(&"r") => (WatchKind::Read, &arguments[1..]),
+        Some(&"w") => (WatchKind::Write, &arguments[1..]),
+        Some(&"rw") => (WatchKind::ReadWrite, &arguments[1..]),
+        _ => (WatchKind::Write, arguments),
+    };
+
+    match numbers(arguments)?.as_slice() {
+        [offset] => Some(Watchpoint::memory(kind, cpu.data_address(*offset as u16), 1)),
+        [offset, length] => Some(Watchpoint::memory(
+            kind,
+            cpu.data_address(*offset as u16),
+            *length,
+        )),
+        _ => None,
+    }
+}
@@ -0,0 +1,111 @@
+use crate::cpu::VmErrorKind;
+use crate::gamepad::{Gamepad, GamepadState};
+use crate::interrupts::{self, InterruptController};
+use crate::keyboard::Keyboard;
+use crate::mouse::Mouse;
+use crate::text::TextInput;
+use crate::timer::Timer;
+
+// Cycles per frame for the vertical blank interrupt: 60 frames a second at
+// 1 MHz. Like the timer it follows the cycle count, not the window.
+pub const VBLANK_PERIOD: u64 = 16_667;
+
+/// The devices behind the `in` and `out` ports.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Devices {
+    pub timer: Timer,
+    pub interrupts: InterruptController,
+    pub keyboard: Keyboard,
+    pub text: TextInput,
+    pub mouse: Mouse,
+    pub gamepad: Gamepad,
+    // The number of frames that have started, for the vblank interrupt.
+    pub frame: u64,
+}
+
+impl Devices {
+    pub fn read(&mut self, port: u16) -> Result<u16, VmErrorKind> {
+        self.timer
+            .read(port)
+            .or_else(|| self.interrupts.read(port))
+            .or_else(|| self.keyboard.read(port))
+            .or_else(|| self.text.read(port))
+            .or_else(|| self.mouse.read(port))
+            .or_else(|| self.gamepad.read(port))
+            .ok_or(VmErrorKind::InvalidPort(port))
+    }
+
+    pub fn write(&mut self, port: u16, value: u16, cycles: u64) -> Result<(), VmErrorKind> {
+        self.timer
+            .write(port, value, cycles)
+            .or_else(|| self.interrupts.write(port, value))
+            .or_else(|| self.keyboard.write(port, value))
+            .or_else(|| self.text.write(port, value))
+            .or_else(|| self.mouse.write(port, value))
+            .or_else(|| self.gamepad.write(port, value))
+            .ok_or(VmErrorKind::InvalidPort(port))
+    }
+
+    /// Queues a key event and raises the keyboard interrupt for it.
+    pub fn key_event(&mut self, keycode: u16, pressed: bool, repeat: bool) {
+        if self.keyboard.event(keycode, pressed, repeat) {
+            self.interrupts.raise(interrupts::IRQ_KEYBOARD);
+        }
+    }
+
+    /// Queues typed text and raises the keyboard interrupt for it.
+    pub fn text_input(&mut self, text: &str) {
+        if self.text.push(text) {
+            self.interrupts.raise(interrupts::IRQ_KEYBOARD);
+        }
+    }
+
+    /// Moves the pointer, raising the mouse interrupt if the guest enabled it.
+    pub fn mouse_move(&mut self, x: u16, y: u16, inside: bool) {
+        let changed = self.mouse.move_to(x, y, inside);
+        self.mouse_changed(changed);
+    }
+
+    pub fn mouse_button(&mut self, button: u16, pressed: bool) {
+        let changed = self.mouse.button(button, pressed);
+        self.mouse_changed(changed);
+    }
+
+    pub fn mouse_scroll(&mut self, lines: i16) {
+        let changed = self.mouse.scroll(lines);
+        self.mouse_changed(changed);
+    }
+
+    fn mouse_changed(&mut self, changed: bool) {
+        if changed && self.mouse.interrupt {
+            self.interrupts.raise(interrupts::IRQ_MOUSE);
+        }
+    }
+
+    /// Updates the controller, raising the gamepad interrupt if the guest
+    /// enabled it and anything changed.
+    pub fn gamepad_update(&mut self, state: GamepadState) {
+        if self.gamepad.update(state) && self.gamepad.interrupt {
+            self.interrupts.raise(interrupts::IRQ_GAMEPAD);
+        }
+    }
+
+    /// Whether `tick` at `cycles` would change anything.
+    pub fn due(&self, cycles: u64) -> bool {
+        self.timer.due(cycles) || cycles / VBLANK_PERIOD != self.frame
+    }
+
+    /// Advances every device to the cycle count of the instruction that just
+    /// finished.
+    pub fn tick(&mut self, cycles: u64) {
+        if self.timer.tick(cycles) {
+            self.interrupts.raise(interrupts::IRQ_TIMER);
+        }
+
+        let frame = cycles / VBLANK_PERIOD;
+        if frame != self.frame {
+            self.frame = frame;
+            self.interrupts.raise(interrupts::IRQ_VBLANK);
+        }
+    }
+}
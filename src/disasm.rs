@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::cpu::{FunctionCall, MicroCVMCpu, Opcode, OpcodeArgument, OpcodeType};
+
+pub struct Instruction {
+    pub address: u16,
+    pub words: Vec<u16>,
+    // `None` when the words do not decode to an instruction and are shown as data.
+    pub opcode: Option<Opcode>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.words.len() as u16
+    }
+}
+
+/// Decodes the instruction at `address` in the code segment. Words that are
+/// not a valid instruction, or an instruction that would run past `end`, come
+/// back as a single data word.
+pub fn decode(cpu: &MicroCVMCpu, address: u16, end: usize) -> Instruction {
+    let word_at = |offset: u16| {
+        cpu.read_memory(cpu.code_address(address.wrapping_add(offset)))
+            .unwrap_or(0)
+    };
+
+    if let Ok(opcode) = cpu.decode_at(address) {
+        let length = opcode.argument_count + 1;
+        if address as usize + length as usize <= end {
+            return Instruction {
+                address,
+                words: (0..length).map(word_at).collect(),
+                opcode: Some(opcode),
+            };
+        }
+    }
+
+    Instruction {
+        address,
+        words: vec![word_at(0)],
+        opcode: None,
+    }
+}
+
+/// Disassembles the first `word_count` words of the code segment into a
+/// listing of address, raw words and assembly. Branch and `call` targets get
+/// synthesized labels so the listing reads like the original source.
+pub fn disassemble(cpu: &MicroCVMCpu, word_count: usize) -> String {
+    let mut instructions = Vec::new();
+    let mut address = 0;
+
+    while (address as usize) < word_count {
+        let instruction = decode(cpu, address, word_count);
+        address = address.wrapping_add(instruction.len());
+        instructions.push(instruction);
+
+        if address == 0 {
+            break;
+        }
+    }
+
+    let labels = synthesize_labels(&instructions);
+    let mut listing = String::new();
+
+    for instruction in &instructions {
+        if let Some(label) = labels.get(&instruction.address) {
+            let _ = writeln!(listing, "\n{}:", label);
+        }
+
+        let words = instruction
+            .words
+            .iter()
+            .map(|word| format!("{:04X}", word))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let _ = writeln!(
+            listing,
+            "{:04X}:  {:<14}    {}",
+            instruction.address,
+            words,
+            format_instruction(instruction, &labels)
+        );
+    }
+
+    listing
+}
+
+// Calls get `sub_XXXX` labels and every other branch target `label_XXXX`.
+pub fn synthesize_labels(instructions: &[Instruction]) -> HashMap<u16, String> {
+    let mut labels = HashMap::new();
+
+    for opcode in instructions.iter().filter_map(|i| i.opcode.as_ref()) {
+        if let (true, Some(OpcodeArgument::Immediate(target))) =
+            (is_branch(opcode.opcode_type), opcode.arg1)
+        {
+            let prefix = if opcode.opcode_type == OpcodeType::Call {
+                "sub"
+            } else {
+                "label"
+            };
+
+            let existing = labels.get(&target).map(String::as_str);
+            if existing.is_none_or(|label: &str| !label.starts_with("sub")) {
+                labels.insert(target, format!("{}_{:04X}", prefix, target));
+            }
+        }
+    }
+
+    labels
+}
+
+pub fn format_instruction(instruction: &Instruction, labels: &HashMap<u16, String>) -> String {
+    let Some(opcode) = &instruction.opcode else {
+        let [low, high] = instruction.words[0].to_le_bytes();
+        return format!("ds {:#04X}, {:#04X}", low, high);
+    };
+
+    let operands = [opcode.arg1, opcode.arg2]
+        .into_iter()
+        .flatten()
+        .map(|argument| format_operand(opcode.opcode_type, argument, labels))
+        .collect::<Vec<_>>();
+
+    if operands.is_empty() {
+        opcode.opcode_type.to_string()
+    } else {
+        format!("{} {}", opcode.opcode_type, operands.join(", "))
+    }
+}
+
+fn format_operand(
+    opcode_type: OpcodeType,
+    argument: OpcodeArgument,
+    labels: &HashMap<u16, String>,
+) -> String {
+    let value = match argument {
+        OpcodeArgument::Register(register) => return register.to_string(),
+        OpcodeArgument::Immediate(value) => value,
+    };
+
+    if opcode_type == OpcodeType::Sys
+        && let Ok(function) = FunctionCall::try_from(value)
+    {
+        return function.to_string();
+    }
+
+    if is_branch(opcode_type) {
+        return match labels.get(&value) {
+            Some(label) => label.clone(),
+            None => format!("{:#06X}", value),
+        };
+    }
+
+    if value <= 0xFF {
+        value.to_string()
+    } else {
+        format!("{:#06X}", value)
+    }
+}
+
+pub fn is_branch(opcode_type: OpcodeType) -> bool {
+    matches!(
+        opcode_type,
+        OpcodeType::Jmp
+            | OpcodeType::Call
+            | OpcodeType::Je
+            | OpcodeType::Jne
+            | OpcodeType::Jl
+            | OpcodeType::Jg
+            | OpcodeType::Jle
+            | OpcodeType::Jge
+            | OpcodeType::Jb
+            | OpcodeType::Ja
+            | OpcodeType::Jc
+            | OpcodeType::Jo
+    )
+}
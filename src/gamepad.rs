@@ -0,0 +1,394 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const STATUS: u16 = 0x70;
+pub const BUTTONS: u16 = 0x71;
+pub const LEFT_X: u16 = 0x72;
+pub const LEFT_Y: u16 = 0x73;
+pub const RIGHT_X: u16 = 0x74;
+pub const RIGHT_Y: u16 = 0x75;
+pub const CONTROL: u16 = 0x76;
+
+pub const STATUS_CONNECTED: u16 = 0x0001;
+pub const STATUS_CHANGED: u16 = 0x0002;
+
+pub const CONTROL_INTERRUPT: u16 = 0x0001;
+
+pub const BUTTON_A: u16 = 0x0001;
+pub const BUTTON_B: u16 = 0x0002;
+pub const BUTTON_X: u16 = 0x0004;
+pub const BUTTON_Y: u16 = 0x0008;
+pub const BUTTON_L: u16 = 0x0010;
+pub const BUTTON_R: u16 = 0x0020;
+pub const BUTTON_SELECT: u16 = 0x0040;
+pub const BUTTON_START: u16 = 0x0080;
+pub const BUTTON_UP: u16 = 0x0100;
+pub const BUTTON_DOWN: u16 = 0x0200;
+pub const BUTTON_LEFT: u16 = 0x0400;
+pub const BUTTON_RIGHT: u16 = 0x0800;
+
+const BUTTON_NAMES: [(&str, u16); 12] = [
+    ("a", BUTTON_A),
+    ("b", BUTTON_B),
+    ("x", BUTTON_X),
+    ("y", BUTTON_Y),
+    ("l", BUTTON_L),
+    ("r", BUTTON_R),
+    ("select", BUTTON_SELECT),
+    ("start", BUTTON_START),
+    ("up", BUTTON_UP),
+    ("down", BUTTON_DOWN),
+    ("left", BUTTON_LEFT),
+    ("right", BUTTON_RIGHT),
+];
+
+// In the order of the axis ports.
+const AXIS_NAMES: [&str; 4] = ["left-x", "left-y", "right-x", "right-y"];
+
+// The keyboard backend's layout, by the keycodes in keycodes.rs.
+const KEY_BUTTONS: [(u16, u16); 12] = [
+    (0x5A, BUTTON_A),      // Z
+    (0x58, BUTTON_B),      // X
+    (0x41, BUTTON_X),      // A
+    (0x53, BUTTON_Y),      // S
+    (0x51, BUTTON_L),      // Q
+    (0x57, BUTTON_R),      // W
+    (0xA1, BUTTON_SELECT), // right Shift
+    (0x0D, BUTTON_START),  // Enter
+    (0x26, BUTTON_UP),
+    (0x28, BUTTON_DOWN),
+    (0x25, BUTTON_LEFT),
+    (0x27, BUTTON_RIGHT),
+];
+
+/// What a controller reports at one moment. Axes run from -32767 to 32767,
+/// positive to the right and down.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GamepadState {
+    pub connected: bool,
+    pub buttons: u16,
+    pub axes: [i16; 4],
+}
+
+/// The controller as the guest sees it, updated from a [`Backend`] before
+/// every instruction.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Gamepad {
+    pub state: GamepadState,
+    // Set when the state changed; cleared by reading `STATUS`.
+    pub changed: bool,
+    pub interrupt: bool,
+}
+
+impl Gamepad {
+    /// Returns whether the state changed.
+    pub fn update(&mut self, state: GamepadState) -> bool {
+        if state == self.state {
+            return false;
+        }
+
+        self.state = state;
+        self.changed = true;
+        true
+    }
+
+    pub fn read(&mut self, port: u16) -> Option<u16> {
+        let value = match port {
+            STATUS => {
+                let mut status = 0;
+                if self.state.connected {
+                    status |= STATUS_CONNECTED;
+                }
+                if std::mem::take(&mut self.changed) {
+                    status |= STATUS_CHANGED;
+                }
+                status
+            }
+            BUTTONS => self.state.buttons,
+            LEFT_X => self.state.axes[0] as u16,
+            LEFT_Y => self.state.axes[1] as u16,
+            RIGHT_X => self.state.axes[2] as u16,
+            RIGHT_Y => self.state.axes[3] as u16,
+            CONTROL => self.interrupt as u16 * CONTROL_INTERRUPT,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    pub fn write(&mut self, port: u16, value: u16) -> Option<()> {
+        match port {
+            CONTROL => self.interrupt = value & CONTROL_INTERRUPT != 0,
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+/// A host source of controller input.
+///
+/// The CPU polls its backend with the cycle count before every instruction,
+/// so a backend that only depends on the cycle count and the key events it is
+/// given plays back the same way in every run.
+pub trait Backend: Send {
+    fn poll(&mut self, cycles: u64) -> GamepadState;
+
+    /// Key presses and releases delivered to the guest, for backends that
+    /// play the controller from the keyboard.
+    fn key_event(&mut self, _keycode: u16, _pressed: bool) {}
+
+    fn box_clone(&self) -> Box<dyn Backend>;
+}
+
+impl Clone for Box<dyn Backend> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Plays the controller from the keyboard: the arrow keys are the D-pad, Z,
+/// X, A, S, Q and W are A, B, X, Y, L and R, Enter is Start and right Shift
+/// is Select. The guest still receives the keys.
+#[derive(Debug, Default, Clone)]
+pub struct KeyboardBackend {
+    buttons: u16,
+}
+
+impl Backend for KeyboardBackend {
+    fn poll(&mut self, _cycles: u64) -> GamepadState {
+        GamepadState {
+            connected: true,
+            buttons: self.buttons,
+            axes: [0; 4],
+        }
+    }
+
+    fn key_event(&mut self, keycode: u16, pressed: bool) {
+        if let Some(&(_, button)) = KEY_BUTTONS.iter().find(|(key, _)| *key == keycode) {
+            if pressed {
+                self.buttons |= button;
+            } else {
+                self.buttons &= !button;
+            }
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Connect,
+    Disconnect,
+    Press(u16),
+    Release(u16),
+    Axis(usize, i16),
+}
+
+/// Plays back a script of controller changes at fixed cycle counts, for
+/// reproducible runs without a controller.
+///
+/// Each line of a script is a cycle count followed by one change:
+///
+/// ```text
+/// # cycles  change
+/// 0         connect
+/// 20000     press right a
+/// 60000     release a
+/// 60000     axis left-y -32767
+/// 90000     disconnect
+/// ```
+///
+/// Changes must be in order. Anything after `#` is a comment.
+#[derive(Debug, Clone)]
+pub struct ScriptedBackend {
+    script: Arc<[(u64, Action)]>,
+    // The next change to apply, and the state after the ones before it.
+    next: usize,
+    state: GamepadState,
+}
+
+impl ScriptedBackend {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut script = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.is_empty() {
+                continue;
+            }
+
+            let (cycles, actions) =
+                parse_line(&words).map_err(|e| format!("line {}: {}", number + 1, e))?;
+
+            if script.last().is_some_and(|&(last, _)| cycles < last) {
+                return Err(format!("line {}: changes must be in order", number + 1));
+            }
+
+            script.extend(actions.into_iter().map(|action| (cycles, action)));
+        }
+
+        Ok(Self {
+            script: script.into(),
+            next: 0,
+            state: GamepadState::default(),
+        })
+    }
+}
+
+impl Backend for ScriptedBackend {
+    fn poll(&mut self, cycles: u64) -> GamepadState {
+        // Rewinding in the debugger goes back in time, so start over.
+        if self.next > 0 && self.script[self.next - 1].0 > cycles {
+            self.next = 0;
+            self.state = GamepadState::default();
+        }
+
+        while let Some(&(at, action)) = self.script.get(self.next) {
+            if at > cycles {
+                break;
+            }
+
+            match action {
+                Action::Connect => self.state.connected = true,
+                Action::Disconnect => self.state = GamepadState::default(),
+                Action::Press(button) => self.state.buttons |= button,
+                Action::Release(button) => self.state.buttons &= !button,
+                Action::Axis(axis, value) => self.state.axes[axis] = value,
+            }
+
+            self.next += 1;
+        }
+
+        self.state
+    }
+
+    fn box_clone(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+}
+
+fn parse_line(words: &[&str]) -> Result<(u64, Vec<Action>), String> {
+    let cycles = words[0]
+        .parse::<u64>()
+        .map_err(|e| format!("invalid cycle count `{}`: {}", words[0], e))?;
+
+    let actions = match &words[1..] {
+        ["connect"] => vec![Action::Connect],
+        ["disconnect"] => vec![Action::Disconnect],
+        [action @ ("press" | "release"), buttons @ ..] if !buttons.is_empty() => buttons
+            .iter()
+            .map(|name| {
+                let button = parse_button(name)?;
+                Ok(match *action {
+                    "press" => Action::Press(button),
+                    _ => Action::Release(button),
+                })
+            })
+            .collect::<Result<_, String>>()?,
+        ["axis", name, value] => {
+            let axis = AXIS_NAMES
+                .iter()
+                .position(|axis| axis == name)
+                .ok_or_else(|| format!("unknown axis `{}`", name))?;
+            let value = value
+                .parse::<i16>()
+                .ok()
+                .filter(|&value| value != i16::MIN)
+                .ok_or_else(|| format!("axis value `{}` is not between -32767 and 32767", value))?;
+            vec![Action::Axis(axis, value)]
+        }
+        _ => {
+            return Err(
+                "expected connect, disconnect, press BUTTON..., release BUTTON... or axis AXIS VALUE"
+                    .to_string(),
+            );
+        }
+    };
+
+    Ok((cycles, actions))
+}
+
+fn parse_button(name: &str) -> Result<u16, String> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(button, _)| *button == name)
+        .map(|&(_, button)| button)
+        .ok_or_else(|| format!("unknown button `{}`", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "\
+# cycles  change
+0         connect
+100       press right a   # two buttons at once
+250       release a
+250       axis left-y -32767
+400       disconnect
+";
+
+    #[test]
+    fn scripted_changes_apply_at_their_cycle() {
+        let mut backend = ScriptedBackend::parse(SCRIPT).unwrap();
+
+        let connected = GamepadState {
+            connected: true,
+            ..GamepadState::default()
+        };
+        let pressed = GamepadState {
+            buttons: BUTTON_RIGHT | BUTTON_A,
+            ..connected
+        };
+        let released = GamepadState {
+            buttons: BUTTON_RIGHT,
+            axes: [0, -32767, 0, 0],
+            ..connected
+        };
+
+        assert_eq!(backend.poll(0), connected);
+        assert_eq!(backend.poll(99), connected);
+        assert_eq!(backend.poll(100), pressed);
+        assert_eq!(backend.poll(249), pressed);
+        // Polling skips cycles; everything due by then has been applied.
+        assert_eq!(backend.poll(300), released);
+        assert_eq!(backend.poll(400), GamepadState::default());
+        assert_eq!(backend.poll(10_000), GamepadState::default());
+    }
+
+    #[test]
+    fn going_back_in_time_replays_the_script() {
+        let mut backend = ScriptedBackend::parse(SCRIPT).unwrap();
+        let states = (0..500).step_by(7).map(|cycles| backend.poll(cycles)).collect::<Vec<_>>();
+
+        assert_eq!(backend.poll(120).buttons, BUTTON_RIGHT | BUTTON_A);
+        let replayed = (0..500).step_by(7).map(|cycles| backend.poll(cycles)).collect::<Vec<_>>();
+        assert_eq!(states, replayed);
+    }
+
+    #[test]
+    fn rejects_invalid_scripts() {
+        let error = |source| ScriptedBackend::parse(source).unwrap_err();
+
+        assert_eq!(error("10 connect\n5 press a"), "line 2: changes must be in order");
+        assert_eq!(error("10 press turbo"), "line 1: unknown button `turbo`");
+        assert_eq!(
+            error("10 axis left-x -32768"),
+            "line 1: axis value `-32768` is not between -32767 and 32767"
+        );
+        assert!(ScriptedBackend::parse("soon connect").is_err());
+        assert!(ScriptedBackend::parse("10 press").is_err());
+    }
+}
@@ -0,0 +1,594 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+
+use crate::cpu::{MicroCVMCpu, Register, VmError, VmErrorKind};
+use crate::trace::{self, Tracer};
+use crate::watch::{HitLocation, WatchHit, WatchKind, WatchTarget, Watchpoint};
+
+// Registers in GDB numbering: r0-r7, v0-v15, k0, then pc, sp, flags, cs, ds.
+const GENERAL_REGISTERS: [Register; 25] = [
+    Register::R0,
+    Register::R1,
+    Register::R2,
+    Register::R3,
+    Register::R4,
+    Register::R5,
+    Register::R6,
+    Register::R7,
+    Register::V0,
+    Register::V1,
+    Register::V2,
+    Register::V3,
+    Register::V4,
+    Register::V5,
+    Register::V6,
+    Register::V7,
+    Register::V8,
+    Register::V9,
+    Register::V10,
+    Register::V11,
+    Register::V12,
+    Register::V13,
+    Register::V14,
+    Register::V15,
+    Register::K0,
+];
+const SPECIAL_REGISTERS: [&str; 5] = ["pc", "sp", "flags", "cs", "ds"];
+const REGISTER_COUNT: usize = GENERAL_REGISTERS.len() + SPECIAL_REGISTERS.len();
+
+// Largest packet advertised in qSupported, in bytes of packet data.
+const PACKET_SIZE: usize = 0x1000;
+
+// How often a running guest checks the socket for a Ctrl-C from GDB.
+const INTERRUPT_POLL_INSTRUCTIONS: u32 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    Detached,
+    Killed,
+}
+
+enum Stop {
+    Signal(u8),
+    Watchpoint(WatchHit),
+    Exited,
+}
+
+/// Waits for GDB to connect on `address` and serves the Remote Serial
+/// Protocol until it detaches or kills the target.
+///
+/// `pc` and breakpoint addresses are word offsets into the code segment, as
+/// in the VM; `m`/`M` memory accesses use physical byte addresses, so the
+/// instruction at `pc` lives at `(cs << 5) + pc * 2`.
+pub fn serve(
+    cpu: &Mutex<MicroCVMCpu>,
+    address: &str,
+    tracer: Option<&mut Tracer>,
+) -> io::Result<SessionEnd> {
+    let listener = TcpListener::bind(address)?;
+    println!("Waiting for GDB on {}", listener.local_addr()?);
+
+    let (stream, peer) = listener.accept()?;
+    println!("GDB connected from {}", peer);
+    stream.set_nodelay(true)?;
+
+    GdbStub::new(stream, tracer).run(cpu)
+}
+
+struct GdbStub<'a> {
+    stream: TcpStream,
+    breakpoints: BTreeSet<u16>,
+    tracer: Option<&'a mut Tracer>,
+}
+
+impl<'a> GdbStub<'a> {
+    fn new(stream: TcpStream, tracer: Option<&'a mut Tracer>) -> Self {
+        Self {
+            stream,
+            breakpoints: BTreeSet::new(),
+            tracer,
+        }
+    }
+
+    fn run(&mut self, cpu: &Mutex<MicroCVMCpu>) -> io::Result<SessionEnd> {
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                return Ok(SessionEnd::Detached);
+            };
+
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => stop_reply(Stop::Signal(SIGTRAP)),
+                Some(b'g') => read_registers(&cpu.lock().unwrap()),
+                Some(b'G') => write_registers(&mut cpu.lock().unwrap(), &packet[1..]),
+                Some(b'p') => read_register(&cpu.lock().unwrap(), &packet[1..]),
+                Some(b'P') => write_register(&mut cpu.lock().unwrap(), &packet[1..]),
+                Some(b'm') => read_memory(&cpu.lock().unwrap(), &packet[1..]),
+                Some(b'M') => write_memory(&mut cpu.lock().unwrap(), &packet[1..]),
+                Some(b'c') => {
+                    set_resume_address(&mut cpu.lock().unwrap(), &packet[1..]);
+                    let stop = self.resume(cpu, false)?;
+                    stop_reply(stop)
+                }
+                Some(b's') => {
+                    set_resume_address(&mut cpu.lock().unwrap(), &packet[1..]);
+                    let stop = self.resume(cpu, true)?;
+                    stop_reply(stop)
+                }
+                Some(b'Z') => self.insert_point(&mut cpu.lock().unwrap(), &packet[1..]),
+                Some(b'z') => self.remove_point(&mut cpu.lock().unwrap(), &packet[1..]),
+                Some(b'H') => "OK".to_string(),
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(SessionEnd::Detached);
+                }
+                Some(b'k') => return Ok(SessionEnd::Killed),
+                Some(b'q') => query(&packet),
+                _ => String::new(),
+            };
+
+            self.write_packet(&reply)?;
+        }
+    }
+
+    // Returns `None` once GDB closes the connection.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+
+        // A packet with a bad checksum is NAKed and the client resends it.
+        loop {
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut packet = Vec::new();
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+
+                if byte[0] == b'#' {
+                    break;
+                }
+                packet.push(byte[0]);
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            let actual = packet.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+            if expected != Some(actual) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+
+            self.stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&packet).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()?;
+
+        // Wait for the acknowledgement and resend on a NAK.
+        let mut ack = [0];
+        loop {
+            if self.stream.read(&mut ack)? == 0 {
+                return Ok(());
+            }
+
+            match ack[0] {
+                b'+' => return Ok(()),
+                b'-' => {
+                    write!(self.stream, "${}#{:02x}", data, checksum)?;
+                    self.stream.flush()?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Executes one instruction for a step, or runs until a breakpoint,
+    // watchpoint, fault, `hlt` or an interrupt from GDB. The CPU lock is only
+    // held for one instruction at a time so the window keeps drawing.
+    fn resume(&mut self, cpu: &Mutex<MicroCVMCpu>, single_step: bool) -> io::Result<Stop> {
+        let mut instructions = 0u32;
+
+        loop {
+            {
+                let mut cpu = cpu.lock().unwrap();
+
+                if cpu.is_halted() {
+                    return Ok(Stop::Exited);
+                }
+
+                if let Err(e) = trace::step(&mut cpu, self.tracer.as_deref_mut()) {
+                    return Ok(Stop::Signal(fault_signal(e)));
+                }
+
+                if let Some(hit) = cpu.watch_hits.first().copied() {
+                    cpu.watch_hits.clear();
+                    return Ok(Stop::Watchpoint(hit));
+                }
+
+                if single_step || self.breakpoints.contains(&cpu.pc) {
+                    return Ok(Stop::Signal(SIGTRAP));
+                }
+            }
+
+            instructions = instructions.wrapping_add(1);
+            if instructions.is_multiple_of(INTERRUPT_POLL_INSTRUCTIONS) && self.interrupt_requested()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // `Z0`/`Z1` are breakpoints on a code offset; `Z2`, `Z3` and `Z4` are
+    // write, read and access watchpoints on physical byte addresses.
+    fn insert_point(&mut self, cpu: &mut MicroCVMCpu, arguments: &str) -> String {
+        match parse_point(arguments) {
+            Some((0 | 1, address, _)) => match u16::try_from(address) {
+                Ok(address) => {
+                    self.breakpoints.insert(address);
+                    "OK".to_string()
+                }
+                Err(_) => "E01".to_string(),
+            },
+            Some((kind @ 2..=4, address, length)) => {
+                cpu.watchpoints
+                    .push(Watchpoint::memory(watch_kind(kind), address, length));
+                "OK".to_string()
+            }
+            Some(_) => String::new(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn remove_point(&mut self, cpu: &mut MicroCVMCpu, arguments: &str) -> String {
+        match parse_point(arguments) {
+            Some((0 | 1, address, _)) => {
+                if let Ok(address) = u16::try_from(address) {
+                    self.breakpoints.remove(&address);
+                }
+                "OK".to_string()
+            }
+            Some((kind @ 2..=4, address, length)) => {
+                let watchpoint = Watchpoint::memory(watch_kind(kind), address, length);
+                if let Some(index) = cpu.watchpoints.iter().position(|w| *w == watchpoint) {
+                    cpu.watchpoints.remove(index);
+                }
+                "OK".to_string()
+            }
+            Some(_) => String::new(),
+            None => "E01".to_string(),
+        }
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Watchpoint(hit) => {
+            let name = match hit.watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::ReadWrite => "awatch",
+            };
+
+            // Report the watched address the access touched first.
+            let address = match (hit.watchpoint.target, hit.location) {
+                (WatchTarget::Memory { start, .. }, HitLocation::Memory { address, .. }) => {
+                    address.max(start)
+                }
+                _ => 0,
+            };
+
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+        }
+        Stop::Exited => "W00".to_string(),
+    }
+}
+
+fn watch_kind(kind: u8) -> WatchKind {
+    match kind {
+        2 => WatchKind::Write,
+        3 => WatchKind::Read,
+        _ => WatchKind::ReadWrite,
+    }
+}
+
+fn fault_signal(error: VmError) -> u8 {
+    match error.kind {
+        VmErrorKind::InvalidOpcode(_)
+        | VmErrorKind::InvalidRegister(_)
+        | VmErrorKind::InvalidFunctionCall(_)
+        | VmErrorKind::BadOperand(_)
+        | VmErrorKind::UnhandledInterrupt(_) => SIGILL,
+        VmErrorKind::DivisionByZero => SIGFPE,
+        VmErrorKind::MemoryFault(_)
+        | VmErrorKind::StackOverflow
+        | VmErrorKind::StackUnderflow
+        | VmErrorKind::InvalidPort(_)
+        | VmErrorKind::FunctionFailed(_) => SIGSEGV,
+    }
+}
+
+fn query(packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+        return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+    }
+
+    if packet == "qAttached" {
+        return "1".to_string();
+    }
+
+    if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        let Some((offset, length)) = range.split_once(',') else {
+            return "E01".to_string();
+        };
+        let (Ok(offset), Ok(length)) = (
+            usize::from_str_radix(offset, 16),
+            usize::from_str_radix(length, 16),
+        ) else {
+            return "E01".to_string();
+        };
+
+        let description = target_description();
+        let chunk = description.get(offset..).unwrap_or("");
+        return if chunk.len() > length {
+            format!("m{}", &chunk[..length])
+        } else {
+            format!("l{}", chunk)
+        };
+    }
+
+    String::new()
+}
+
+// GDB has no MicroCVM architecture to name, so the description carries no
+// `<architecture>` element and only lists the registers.
+fn target_description() -> String {
+    let mut description = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.microcvm.core\">",
+    );
+
+    let names = GENERAL_REGISTERS
+        .iter()
+        .map(|register| register.to_string())
+        .chain(SPECIAL_REGISTERS.iter().map(|name| name.to_string()));
+
+    for (number, name) in names.enumerate() {
+        description.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"16\" type=\"uint16\" regnum=\"{}\"/>",
+            name, number
+        ));
+    }
+
+    description.push_str("</feature></target>");
+    description
+}
+
+fn register_value(cpu: &MicroCVMCpu, number: usize) -> Option<u16> {
+    if let Some(register) = GENERAL_REGISTERS.get(number) {
+        return Some(cpu.registers[register.index()]);
+    }
+
+    match number - GENERAL_REGISTERS.len() {
+        0 => Some(cpu.pc),
+        1 => Some(cpu.sp),
+        2 => Some(cpu.flags),
+        3 => Some(cpu.registers[Register::CS.index()]),
+        4 => Some(cpu.registers[Register::DS.index()]),
+        _ => None,
+    }
+}
+
+fn set_register_value(cpu: &mut MicroCVMCpu, number: usize, value: u16) -> bool {
+    if let Some(register) = GENERAL_REGISTERS.get(number) {
+        cpu.registers[register.index()] = value;
+        return true;
+    }
+
+    match number - GENERAL_REGISTERS.len() {
+        0 => cpu.pc = value,
+        1 => cpu.sp = value,
+        2 => cpu.flags = value,
+        3 => cpu.registers[Register::CS.index()] = value,
+        4 => cpu.registers[Register::DS.index()] = value,
+        _ => return false,
+    }
+
+    true
+}
+
+// Register values go over the wire as little-endian hex bytes.
+fn encode_word(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+fn decode_word(hex: &str) -> Option<u16> {
+    let bytes = decode_hex(hex)?;
+    match bytes.as_slice() {
+        [low, high] => Some(u16::from_le_bytes([*low, *high])),
+        _ => None,
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn read_registers(cpu: &MicroCVMCpu) -> String {
+    (0..REGISTER_COUNT)
+        .filter_map(|number| register_value(cpu, number))
+        .map(encode_word)
+        .collect()
+}
+
+fn write_registers(cpu: &mut MicroCVMCpu, hex: &str) -> String {
+    if hex.len() != REGISTER_COUNT * 4 {
+        return "E01".to_string();
+    }
+
+    let Some(values) = (0..REGISTER_COUNT)
+        .map(|number| decode_word(hex.get(number * 4..number * 4 + 4)?))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return "E01".to_string();
+    };
+
+    for (number, value) in values.into_iter().enumerate() {
+        set_register_value(cpu, number, value);
+    }
+
+    "OK".to_string()
+}
+
+fn read_register(cpu: &MicroCVMCpu, arguments: &str) -> String {
+    usize::from_str_radix(arguments, 16)
+        .ok()
+        .and_then(|number| register_value(cpu, number))
+        .map(encode_word)
+        .unwrap_or_else(|| "E01".to_string())
+}
+
+fn write_register(cpu: &mut MicroCVMCpu, arguments: &str) -> String {
+    let written = arguments.split_once('=').and_then(|(number, value)| {
+        let number = usize::from_str_radix(number, 16).ok()?;
+        let value = decode_word(value)?;
+        set_register_value(cpu, number, value).then_some(())
+    });
+
+    match written {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+// `address,length`, rejecting ranges that run past the end of the address
+// space. A watchpoint covers at least one byte, so empty ranges count as one.
+fn parse_range(arguments: &str) -> Option<(usize, usize)> {
+    let (address, length) = arguments.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    address.checked_add(length.max(1))?;
+
+    Some((address, length))
+}
+
+// `type,address,kind` from a Z or z packet.
+fn parse_point(arguments: &str) -> Option<(u8, usize, usize)> {
+    let (kind, range) = arguments.split_once(',')?;
+    let (address, length) = parse_range(range)?;
+    Some((kind.parse().ok()?, address, length))
+}
+
+fn read_memory(cpu: &MicroCVMCpu, arguments: &str) -> String {
+    let Some((address, length)) = parse_range(arguments) else {
+        return "E01".to_string();
+    };
+    // Each byte takes two hex digits; GDB asks again for whatever is missing.
+    let length = length.min(PACKET_SIZE / 2);
+
+    (address..address + length)
+        .map(|address| cpu.read_byte(address).map(|byte| format!("{:02x}", byte)))
+        .collect::<Result<String, _>>()
+        .unwrap_or_else(|_| "E14".to_string())
+}
+
+fn write_memory(cpu: &mut MicroCVMCpu, arguments: &str) -> String {
+    let Some((range, data)) = arguments.split_once(':') else {
+        return "E01".to_string();
+    };
+    let (Some((address, length)), Some(bytes)) = (parse_range(range), decode_hex(data)) else {
+        return "E01".to_string();
+    };
+
+    if bytes.len() != length || cpu.read_byte(address + length.saturating_sub(1)).is_err() {
+        return "E14".to_string();
+    }
+
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        if cpu.write_byte(address + offset, byte).is_err() {
+            return "E14".to_string();
+        }
+    }
+
+    "OK".to_string()
+}
+
+// `c` and `s` may carry an address to resume from.
+fn set_resume_address(cpu: &mut MicroCVMCpu, arguments: &str) {
+    if let Ok(address) = u16::from_str_radix(arguments, 16) {
+        cpu.pc = address;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_register_writes_are_rejected() {
+        let mut cpu = MicroCVMCpu::from_source("hlt\n");
+        let registers = read_registers(&cpu);
+        assert_eq!(write_registers(&mut cpu, &registers), "OK");
+
+        // Multi-byte characters make the byte length right but the word
+        // boundaries fall inside them.
+        let non_ascii = format!("a{}a", "\u{e9}".repeat(REGISTER_COUNT * 2 - 1));
+        assert_eq!(non_ascii.len(), REGISTER_COUNT * 4);
+        assert_eq!(write_registers(&mut cpu, &non_ascii), "E01");
+        assert_eq!(write_registers(&mut cpu, &registers[4..]), "E01");
+    }
+
+    #[test]
+    fn memory_reads_fit_in_a_packet() {
+        let cpu = MicroCVMCpu::from_source("hlt\n");
+        assert_eq!(read_memory(&cpu, "0,4").len(), 8);
+        assert_eq!(read_memory(&cpu, "0,10000").len(), PACKET_SIZE);
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::{MicroCVMCpu, VmError};
+use crate::trace::{self, Tracer};
+use crate::watch::{self, WatchHit};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Halted,
+    InstructionLimit,
+    Fault(VmError),
+    Watchpoint(WatchHit),
+    ReplayFinished,
+}
+
+pub struct RunSummary {
+    pub reason: ExitReason,
+    pub instructions: u64,
+}
+
+/// Runs the CPU without a window until it reaches `hlt`, faults, executes
+/// `max_instructions` instructions or reaches the end of an input replay,
+/// whichever comes first. Watchpoint hits are logged, and also stop the run
+/// when `watch_pause` is set.
+pub fn run(
+    cpu: &mut MicroCVMCpu,
+    max_instructions: Option<u64>,
+    mut tracer: Option<&mut Tracer>,
+    watch_pause: bool,
+) -> RunSummary {
+    let mut instructions = 0;
+
+    loop {
+        if cpu.is_halted() {
+            return RunSummary {
+                reason: ExitReason::Halted,
+                instructions,
+            };
+        }
+
+        if cpu.input_replay_finished() {
+            return RunSummary {
+                reason: ExitReason::ReplayFinished,
+                instructions,
+            };
+        }
+
+        if max_instructions.is_some_and(|limit| instructions >= limit) {
+            return RunSummary {
+                reason: ExitReason::InstructionLimit,
+                instructions,
+            };
+        }
+
+        if let Err(e) = trace::step(cpu, tracer.as_deref_mut()) {
+            return RunSummary {
+                reason: ExitReason::Fault(e),
+                instructions,
+            };
+        }
+
+        instructions += 1;
+
+        if let Some(hit) = watch::report_hits(cpu, watch_pause) {
+            return RunSummary {
+                reason: ExitReason::Watchpoint(hit),
+                instructions,
+            };
+        }
+    }
+}
+
+/// Writes the visible part of `video_memory` to `path`. The format is picked
+/// from the extension: `.ppm` writes a binary PPM, anything else a PNG.
+pub fn dump_framebuffer(cpu: &MicroCVMCpu, path: &Path) -> io::Result<()> {
+    let width = cpu.framebuffer_width;
+    let height = cpu.framebuffer_height;
+
+    let is_ppm = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ppm"));
+
+    let writer = BufWriter::new(File::create(path)?);
+
+    if is_ppm {
+        write_ppm(cpu, writer, width, height)
+    } else {
+        write_png(cpu, writer, width, height)
+    }
+}
+
+fn write_ppm(
+    cpu: &MicroCVMCpu,
+    mut writer: impl Write,
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+
+    for color in &cpu.video_memory[..width * height] {
+        writer.write_all(&[color.r, color.g, color.b])?;
+    }
+
+    writer.flush()
+}
+
+fn write_png(cpu: &MicroCVMCpu, writer: impl Write, width: usize, height: usize) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data = Vec::with_capacity(width * height * 4);
+    for color in &cpu.video_memory[..width * height] {
+        data.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+    }
+
+    let mut png_writer = encoder.write_header().map_err(io::Error::other)?;
+    png_writer.write_image_data(&data).map_err(io::Error::other)?;
+
+    Ok(())
+}
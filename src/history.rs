@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+
+use crate::cpu::{MicroCVMCpu, Register, VmError};
+use crate::devices::Devices;
+use crate::snapshot;
+use crate::trace::{self, Tracer};
+use crate::types::Color;
+
+// Instructions that can be undone straight from the log.
+const UNDO_CAPACITY: usize = 100_000;
+
+// A full copy of the machine is kept every CHECKPOINT_INTERVAL instructions so
+// execution can be rewound past the end of the log by replaying from it. The
+// interval must not exceed UNDO_CAPACITY.
+const CHECKPOINT_INTERVAL: u64 = 50_000;
+const MAX_CHECKPOINTS: usize = 4;
+
+/// The values overwritten by one instruction, oldest first.
+#[derive(Debug, Default, Clone)]
+pub struct Journal {
+    // Word addresses and their old values.
+    pub memory: Vec<(usize, u16)>,
+    // Video memory indices and their old colors.
+    pub pixels: Vec<(usize, Color)>,
+    // The devices before the instruction first changed them.
+    pub devices: Option<Devices>,
+}
+
+struct Entry {
+    pc: u16,
+    sp: u16,
+    flags: u16,
+    cycles: u64,
+    registers: [u16; Register::ALL.len()],
+    journal: Journal,
+}
+
+impl Entry {
+    fn wrote(&self, address: usize) -> bool {
+        self.journal.memory.iter().any(|&(word, _)| word == address / 2)
+    }
+}
+
+/// Records executed instructions so they can be undone.
+///
+/// Replaying from a checkpoint re-executes the guest, so it only reproduces
+/// the original run if no input arrived in the meantime.
+#[derive(Default)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    checkpoints: VecDeque<(u64, MicroCVMCpu)>,
+    // The number of instructions executed since recording started.
+    position: u64,
+}
+
+impl History {
+    /// Executes one instruction through `tracer` and records how to undo it.
+    pub fn step(&mut self, cpu: &mut MicroCVMCpu, tracer: Option<&mut Tracer>) -> Result<(), VmError> {
+        if self.position.is_multiple_of(CHECKPOINT_INTERVAL)
+            && self.checkpoints.back().is_none_or(|(position, _)| *position != self.position)
+        {
+            let mut checkpoint = cpu.clone();
+            checkpoint.watch_hits.clear();
+            self.checkpoints.push_back((self.position, checkpoint));
+
+            if self.checkpoints.len() > MAX_CHECKPOINTS {
+                self.checkpoints.pop_front();
+            }
+        }
+
+        let mut entry = Entry {
+            pc: cpu.pc,
+            sp: cpu.sp,
+            flags: cpu.flags,
+            cycles: cpu.cycles,
+            registers: cpu.registers,
+            journal: Journal::default(),
+        };
+
+        cpu.journal = Some(Journal::default());
+        let result = trace::step(cpu, tracer);
+        entry.journal = cpu.journal.take().unwrap_or_default();
+
+        if let Err(e) = result {
+            undo(cpu, &entry);
+            return Err(e);
+        }
+
+        self.entries.push_back(entry);
+        if self.entries.len() > UNDO_CAPACITY {
+            self.entries.pop_front();
+        }
+
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Undoes the last instruction. Returns whether it wrote the physical
+    /// byte `address`, or `None` at the start of the recorded history.
+    pub fn step_back(&mut self, cpu: &mut MicroCVMCpu, address: Option<usize>) -> Option<bool> {
+        if self.entries.is_empty() {
+            self.replay_from_checkpoint(cpu)?;
+        }
+
+        let entry = self.entries.pop_back()?;
+        undo(cpu, &entry);
+        self.position -= 1;
+
+        // Checkpoints past this point belong to a future that may not happen.
+        while self
+            .checkpoints
+            .back()
+            .is_some_and(|(position, _)| *position > self.position)
+        {
+            self.checkpoints.pop_back();
+        }
+
+        Some(address.is_some_and(|address| entry.wrote(address)))
+    }
+
+    /// Drops the checkpoints, which a change made outside the guest would
+    /// make wrong. The undo log stays usable.
+    pub fn forget_checkpoints(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    // Rebuilds the log by restoring the latest checkpoint before the current
+    // position and running forward to it again. If the replay faults before
+    // getting there it took another path, so the machine is put back and the
+    // history ends here.
+    fn replay_from_checkpoint(&mut self, cpu: &mut MicroCVMCpu) -> Option<()> {
+        let target = self.position;
+        let index = self
+            .checkpoints
+            .iter()
+            .rposition(|(position, _)| *position < target)?;
+
+        self.checkpoints.truncate(index + 1);
+        let (position, checkpoint) = &self.checkpoints[index];
+        self.position = *position;
+        let current = cpu.clone();
+        snapshot::restore(cpu, checkpoint.clone());
+
+        while self.position < target {
+            let replayed = self.step(cpu, None);
+            cpu.watch_hits.clear();
+
+            if replayed.is_err() {
+                snapshot::restore(cpu, current);
+                self.entries.clear();
+                self.checkpoints.clear();
+                self.position = target;
+                return None;
+            }
+        }
+
+        Some(())
+    }
+}
+
+fn undo(cpu: &mut MicroCVMCpu, entry: &Entry) {
+    for &(index, color) in entry.journal.pixels.iter().rev() {
+        cpu.video_memory[index] = color;
+    }
+
+    for &(address, value) in entry.journal.memory.iter().rev() {
+        cpu.memory[address] = value;
+    }
+
+    cpu.pc = entry.pc;
+    cpu.sp = entry.sp;
+    cpu.flags = entry.flags;
+    cpu.cycles = entry.cycles;
+    if let Some(devices) = &entry.journal.devices {
+        cpu.devices = devices.clone();
+    }
+    cpu.registers = entry.registers;
+
+    if let Some(replay) = &mut cpu.input_replay {
+        replay.rewind(entry.cycles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_instructions_that_change_the_devices_journal_them() {
+        let mut cpu =
+            MicroCVMCpu::from_source("out 0x41, 1000\nout 0x40, 1\nnop\nin r0, 0x44\nhlt\n");
+        let start = cpu.clone();
+        let mut history = History::default();
+
+        while !cpu.is_halted() {
+            history.step(&mut cpu, None).unwrap();
+        }
+
+        let journaled = history
+            .entries
+            .iter()
+            .map(|entry| entry.journal.devices.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(journaled, [true, true, false, true]);
+
+        while history.step_back(&mut cpu, None).is_some() {}
+        assert_eq!(cpu.devices, start.devices);
+        assert_eq!((cpu.pc, cpu.cycles), (start.pc, start.cycles));
+    }
+
+    #[test]
+    fn a_replay_that_faults_ends_the_history() {
+        let mut cpu = MicroCVMCpu::from_source("loop: add r0, 1\njmp loop\n");
+        let mut history = History::default();
+
+        for _ in 0..UNDO_CAPACITY + 10 {
+            history.step(&mut cpu, None).unwrap();
+        }
+        for _ in 0..UNDO_CAPACITY {
+            history.step_back(&mut cpu, None).unwrap();
+        }
+
+        // Replaying from the first checkpoint now faults on its second step.
+        let (_, checkpoint) = &mut history.checkpoints[0];
+        let jmp = checkpoint.code_address(3);
+        checkpoint.memory[jmp] = 0x00FE;
+
+        let (pc, registers) = (cpu.pc, cpu.registers);
+        assert_eq!(history.step_back(&mut cpu, None), None);
+        assert_eq!((cpu.pc, cpu.registers), (pc, registers));
+        assert_eq!(history.step_back(&mut cpu, None), None);
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::asm::{parse_number, unescape};
+
+const HEADER: &str = "# microcvm input recording";
+
+/// An input event from the host, as delivered to the devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    Key { keycode: u16, pressed: bool, repeat: bool },
+    Text(String),
+    MouseMove { x: u16, y: u16, inside: bool },
+    MouseButton { button: u16, pressed: bool },
+    MouseScroll(i16),
+}
+
+/// One line of a recording, without the cycle count.
+impl Display for InputEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputEvent::Key { keycode, pressed, repeat } => {
+                let action = match (pressed, repeat) {
+                    (true, false) => "press",
+                    (true, true) => "repeat",
+                    (false, _) => "release",
+                };
+                write!(f, "key 0x{:02X} {}", keycode, action)
+            }
+            InputEvent::Text(text) => write!(f, "text \"{}\"", text.escape_debug()),
+            InputEvent::MouseMove { x, y, inside: true } => write!(f, "mouse {} {}", x, y),
+            InputEvent::MouseMove { x, y, inside: false } => write!(f, "mouse {} {} outside", x, y),
+            InputEvent::MouseButton { button, pressed } => write!(
+                f,
+                "mouse-button 0x{:02X} {}",
+                button,
+                if *pressed { "press" } else { "release" }
+            ),
+            InputEvent::MouseScroll(lines) => write!(f, "wheel {}", lines),
+        }
+    }
+}
+
+/// Appends every delivered event to a file, one line each, stamped with the
+/// cycle count at which it reached the devices.
+///
+/// Lines are written as the events arrive, so a session that ends without
+/// `finish`, e.g. by a crash, can still be replayed up to that point.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    file: Arc<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", HEADER)?;
+
+        Ok(Self { file: Arc::new(file) })
+    }
+
+    pub fn record(&self, cycles: u64, event: &InputEvent) -> io::Result<()> {
+        writeln!(&*self.file, "{} {}", cycles, event)
+    }
+
+    /// Marks the end of the session, where a headless replay stops.
+    pub fn finish(&self, cycles: u64) -> io::Result<()> {
+        writeln!(&*self.file, "{} end", cycles)
+    }
+}
+
+/// Delivers the events of a recording at the cycle counts they were recorded
+/// at, which are the same instruction boundaries as long as the machine
+/// started from the same image or snapshot.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    events: Arc<[(u64, InputEvent)]>,
+    end: Option<u64>,
+    // The next event to deliver.
+    next: usize,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+        let mut end = None;
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+
+            if end.is_some() {
+                return Err(error("event after the end of the recording".to_string()));
+            }
+
+            let (cycles, event) = line.split_once(' ').unwrap_or((line, ""));
+            let cycles = cycles
+                .parse::<u64>()
+                .map_err(|e| error(format!("invalid cycle count `{}`: {}", cycles, e)))?;
+
+            if events.last().is_some_and(|&(last, _)| cycles < last) {
+                return Err(error("events must be in order".to_string()));
+            }
+
+            match event.trim() {
+                "end" => end = Some(cycles),
+                event => events.push((cycles, parse_event(event).map_err(error)?)),
+            }
+        }
+
+        Ok(Self {
+            events: events.into(),
+            end,
+            next: 0,
+        })
+    }
+
+    /// Takes the events due by `cycles` that have not been delivered yet.
+    pub fn due(&mut self, cycles: u64) -> Vec<InputEvent> {
+        let start = self.next;
+        while self.events.get(self.next).is_some_and(|&(at, _)| at <= cycles) {
+            self.next += 1;
+        }
+
+        self.events[start..self.next]
+            .iter()
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+
+    /// Delivers the events from `cycles` on again, after the machine went
+    /// back to the state it had before the instruction at `cycles`.
+    pub fn rewind(&mut self, cycles: u64) {
+        self.next = self.events.partition_point(|&(at, _)| at < cycles);
+    }
+
+    /// Whether the recorded session ended by `cycles`.
+    pub fn finished(&self, cycles: u64) -> bool {
+        self.end.is_some_and(|end| cycles >= end)
+    }
+}
+
+fn parse_event(event: &str) -> Result<InputEvent, String> {
+    if let Some(text) = event.strip_prefix("text ") {
+        return text
+            .trim()
+            .strip_prefix('"')
+            .and_then(|text| text.strip_suffix('"'))
+            .and_then(unescape)
+            .map(InputEvent::Text)
+            .ok_or_else(|| format!("invalid text {}", text.trim()));
+    }
+
+    let words = event.split_whitespace().collect::<Vec<_>>();
+    let number = |word: &str| parse_number(word).ok_or_else(|| format!("invalid number `{}`", word));
+    let pressed = |word: &str| match word {
+        "press" => Ok(true),
+        "release" => Ok(false),
+        _ => Err(format!("expected press or release, found `{}`", word)),
+    };
+
+    match words.as_slice() {
+        ["key", keycode, action] => {
+            let (pressed, repeat) = match *action {
+                "repeat" => (true, true),
+                action => (pressed(action)?, false),
+            };
+            Ok(InputEvent::Key {
+                keycode: number(keycode)?,
+                pressed,
+                repeat,
+            })
+        }
+        ["mouse", x, y] => Ok(InputEvent::MouseMove {
+            x: number(x)?,
+            y: number(y)?,
+            inside: true,
+        }),
+        ["mouse", x, y, "outside"] => Ok(InputEvent::MouseMove {
+            x: number(x)?,
+            y: number(y)?,
+            inside: false,
+        }),
+        ["mouse-button", button, action] => Ok(InputEvent::MouseButton {
+            button: number(button)?,
+            pressed: pressed(action)?,
+        }),
+        ["wheel", lines] => lines
+            .parse()
+            .map(InputEvent::MouseScroll)
+            .map_err(|e| format!("invalid wheel lines `{}`: {}", lines, e)),
+        _ => Err(format!("unknown event `{}`", event)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::MicroCVMCpu;
+
+    // Copies typed text to memory and keeps summing the mouse position, so
+    // input delivered even one instruction late changes the result.
+    const PROGRAM: &str = "\
+        mov r3, 0x200
+loop:   in r1, 0x68
+        add r7, r1
+        in r0, 0x65
+        and r0, 1
+        je mouse
+        in r0, 0x64
+        store r3, r0
+        add r3, 2
+mouse:  in r1, 0x68
+        add r7, r1
+        in r1, 0x6B
+        add r6, r1
+        jmp loop
+";
+
+    fn events() -> Vec<InputEvent> {
+        vec![
+            InputEvent::Text("h\u{e9} \"\\\n\u{1F600}".to_string()),
+            InputEvent::Key {
+                keycode: 0x41,
+                pressed: true,
+                repeat: false,
+            },
+            InputEvent::Key {
+                keycode: 0x41,
+                pressed: true,
+                repeat: true,
+            },
+            InputEvent::Key {
+                keycode: 0x41,
+                pressed: false,
+                repeat: false,
+            },
+            InputEvent::MouseMove {
+                x: 12,
+                y: 34,
+                inside: true,
+            },
+            InputEvent::MouseMove {
+                x: 0,
+                y: 479,
+                inside: false,
+            },
+            InputEvent::MouseButton {
+                button: crate::mouse::BUTTON_RIGHT,
+                pressed: true,
+            },
+            InputEvent::MouseScroll(-3),
+        ]
+    }
+
+    #[test]
+    fn events_round_trip_through_their_lines() {
+        let source = events()
+            .iter()
+            .enumerate()
+            .map(|(index, event)| format!("{} {}\n", index * 10, event))
+            .collect::<String>();
+        let mut replay = Replay::parse(&source).unwrap();
+
+        assert_eq!(replay.due(u64::MAX), events());
+    }
+
+    #[test]
+    fn replay_reproduces_a_recorded_session() {
+        let path = std::env::temp_dir().join(format!("microcvm-input-{}.txt", std::process::id()));
+
+        let mut recorded = MicroCVMCpu::from_source(PROGRAM);
+        recorded.input_recorder = Some(Recorder::create(&path).unwrap());
+        let mut events = events().into_iter().cycle();
+        for step in 0..3000 {
+            if step % 97 == 0 {
+                recorded.input(events.next().unwrap());
+                recorded.input(InputEvent::MouseMove {
+                    x: step as u16,
+                    y: 0,
+                    inside: true,
+                });
+            }
+            if step % 250 == 0 {
+                recorded.input(InputEvent::Text("ab".to_string()));
+            }
+            recorded.step().unwrap();
+        }
+        recorded.finish_input_recording();
+
+        let mut replayed = MicroCVMCpu::from_source(PROGRAM);
+        replayed.input_replay = Some(Replay::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        while !replayed.input_replay_finished() {
+            replayed.step().unwrap();
+        }
+
+        assert_eq!(replayed.cycles, recorded.cycles);
+        assert_eq!(replayed.registers, recorded.registers);
+        assert!(replayed.memory == recorded.memory);
+        assert_eq!(replayed.devices, recorded.devices);
+    }
+
+    #[test]
+    fn events_are_delivered_once_unless_rewound() {
+        let mut replay = Replay::parse("5 wheel 1\n5 wheel 2\n9 wheel 3\n12 end\n").unwrap();
+
+        assert_eq!(replay.due(4), []);
+        assert_eq!(replay.due(5), [InputEvent::MouseScroll(1), InputEvent::MouseScroll(2)]);
+        // An instruction that faults leaves the cycle count where it was.
+        assert_eq!(replay.due(5), []);
+        assert_eq!(replay.due(10), [InputEvent::MouseScroll(3)]);
+        assert!(!replay.finished(11));
+        assert!(replay.finished(12));
+
+        replay.rewind(5);
+        assert_eq!(replay.due(9).len(), 3);
+    }
+
+    #[test]
+    fn rejects_invalid_recordings() {
+        let error = |source| Replay::parse(source).unwrap_err();
+
+        assert_eq!(error("10 wheel 1\n5 wheel 1"), "line 2: events must be in order");
+        assert_eq!(error("10 end\n11 wheel 1"), "line 2: event after the end of the recording");
+        assert_eq!(error("10 key 0x41 hold"), "line 1: expected press or release, found `hold`");
+        assert!(Replay::parse("10 text \"\\q\"").is_err());
+        assert!(Replay::parse("soon wheel 1").is_err());
+    }
+}
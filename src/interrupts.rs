@@ -0,0 +1,68 @@
+pub const PENDING: u16 = 0x20;
+pub const MASK: u16 = 0x21;
+
+// Interrupt request lines. Line N is delivered through vector IRQ_BASE + N.
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_VBLANK: u8 = 2;
+pub const IRQ_MOUSE: u8 = 3;
+pub const IRQ_GAMEPAD: u8 = 4;
+pub const IRQ_BASE: u8 = 0x08;
+
+/// Collects interrupt requests from the devices until the CPU delivers them.
+///
+/// A line is pending from the moment its device raises it until the CPU
+/// jumps to its handler or the guest clears it through `PENDING`. Only lines
+/// whose bit is set in `MASK` are delivered, lowest line first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptController {
+    pub pending: u16,
+    pub mask: u16,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self {
+            pending: 0,
+            mask: 0xFFFF,
+        }
+    }
+}
+
+impl InterruptController {
+    pub fn raise(&mut self, line: u8) {
+        self.pending |= 1 << line;
+    }
+
+    /// The lines with an unmasked request, highest priority first. They stay
+    /// pending until `acknowledge`.
+    pub fn requests(&self) -> impl Iterator<Item = u8> + use<> {
+        let requests = self.pending & self.mask;
+        (0..16).filter(move |line| requests & (1 << line) != 0)
+    }
+
+    /// Clears the request on `line` once the CPU has entered its handler.
+    pub fn acknowledge(&mut self, line: u8) {
+        self.pending &= !(1 << line);
+    }
+
+    pub fn read(&mut self, port: u16) -> Option<u16> {
+        match port {
+            PENDING => Some(self.pending),
+            MASK => Some(self.mask),
+            _ => None,
+        }
+    }
+
+    /// Writing `PENDING` clears the lines whose bits are set, so a guest with
+    /// interrupts disabled can poll and acknowledge requests itself.
+    pub fn write(&mut self, port: u16, value: u16) -> Option<()> {
+        match port {
+            PENDING => self.pending &= !value,
+            MASK => self.mask = value,
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
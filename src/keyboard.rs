@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+
+pub const DATA: u16 = 0x60;
+pub const STATUS: u16 = 0x61;
+pub const MODIFIERS: u16 = 0x62;
+pub const CONTROL: u16 = 0x63;
+
+pub const STATUS_AVAILABLE: u16 = 0x0001;
+pub const STATUS_OVERFLOW: u16 = 0x0002;
+
+pub const MODIFIER_SHIFT: u16 = 0x0100;
+pub const MODIFIER_CONTROL: u16 = 0x0200;
+pub const MODIFIER_ALT: u16 = 0x0400;
+
+pub const EVENT_REPEAT: u16 = 0x4000;
+pub const EVENT_RELEASE: u16 = 0x8000;
+const EVENT_KEYCODE: u16 = 0x00FF;
+
+pub const CONTROL_REPEAT: u16 = 0x0001;
+pub const CONTROL_FLUSH: u16 = 0x0002;
+
+const QUEUE_CAPACITY: usize = 32;
+
+/// A keyboard that queues every press and release instead of keeping only
+/// the latest key.
+///
+/// Each event is one word: the keycode in the low byte, the modifiers held at
+/// the time in bits 8-10, `EVENT_REPEAT` for auto-repeated presses and
+/// `EVENT_RELEASE` for releases. Reading `DATA` takes the oldest event, or 0
+/// when the queue is empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keyboard {
+    pub queue: VecDeque<u16>,
+    // Set when an event was dropped because the queue was full; cleared by
+    // reading `STATUS`.
+    pub overflow: bool,
+    pub repeat: bool,
+    // Keys that are down, in the order they were pressed.
+    pub held: Vec<u16>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            overflow: false,
+            repeat: true,
+            held: Vec::new(),
+        }
+    }
+}
+
+impl Keyboard {
+    /// Records a key event from the host. Returns whether it was queued.
+    pub fn event(&mut self, keycode: u16, pressed: bool, repeat: bool) -> bool {
+        if keycode == 0 {
+            return false;
+        }
+
+        if pressed {
+            if !self.held.contains(&keycode) {
+                self.held.push(keycode);
+            }
+        } else {
+            self.held.retain(|&key| key != keycode);
+        }
+
+        if repeat && !self.repeat {
+            return false;
+        }
+
+        if self.queue.len() == QUEUE_CAPACITY {
+            self.overflow = true;
+            return false;
+        }
+
+        let mut event = (keycode & EVENT_KEYCODE) | self.modifiers();
+        if repeat {
+            event |= EVENT_REPEAT;
+        }
+        if !pressed {
+            event |= EVENT_RELEASE;
+        }
+
+        self.queue.push_back(event);
+        true
+    }
+
+    /// The most recently pressed key that is still down, or 0.
+    pub fn held_key(&self) -> u16 {
+        self.held.last().copied().unwrap_or(0)
+    }
+
+    // Derived from the held keys so that replaying the events reproduces it.
+    fn modifiers(&self) -> u16 {
+        [
+            (0xA0, MODIFIER_SHIFT),
+            (0xA1, MODIFIER_SHIFT),
+            (0xA2, MODIFIER_CONTROL),
+            (0xA3, MODIFIER_CONTROL),
+            (0xA4, MODIFIER_ALT),
+            (0xA5, MODIFIER_ALT),
+        ]
+        .iter()
+        .filter(|(key, _)| self.held.contains(key))
+        .fold(0, |modifiers, (_, modifier)| modifiers | modifier)
+    }
+
+    pub fn read(&mut self, port: u16) -> Option<u16> {
+        let value = match port {
+            DATA => self.queue.pop_front().unwrap_or(0),
+            STATUS => {
+                let mut status = 0;
+                if !self.queue.is_empty() {
+                    status |= STATUS_AVAILABLE;
+                }
+                if std::mem::take(&mut self.overflow) {
+                    status |= STATUS_OVERFLOW;
+                }
+                status
+            }
+            MODIFIERS => self.modifiers(),
+            CONTROL => self.repeat as u16 * CONTROL_REPEAT,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    pub fn write(&mut self, port: u16, value: u16) -> Option<()> {
+        match port {
+            CONTROL => {
+                self.repeat = value & CONTROL_REPEAT != 0;
+                if value & CONTROL_FLUSH != 0 {
+                    self.queue.clear();
+                }
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
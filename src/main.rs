@@ -1,59 +1,330 @@
 //#![windows_subsystem = "windows"]
 
+mod asm;
+mod cli;
+mod clock;
 mod cpu;
+mod debugger;
+mod devices;
+mod disasm;
 mod disk;
+mod gamepad;
+mod gdb;
+mod headless;
+mod history;
+mod input;
+mod interrupts;
+mod keyboard;
+mod mouse;
 mod render;
 mod screen;
+mod snapshot;
+mod text;
+mod timer;
+mod trace;
 mod types;
+mod watch;
 mod keycodes;
 
+use clap::Parser;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use winit::event_loop::{ControlFlow, EventLoop};
 
 fn main() {
-    let vcpu = Arc::new(Mutex::new(cpu::MicroCVMCpu::empty()));
+    let cli = cli::Cli::parse();
 
-    {
-        let mut vcpu_locked = vcpu.lock().unwrap();
-        if let Err(e) = vcpu_locked.read_iso("examples/images/test.iso") {
-            eprintln!("error reading iso: {}", e);
+    match cli.command {
+        Some(cli::Command::Run(args)) => run(*args),
+        Some(cli::Command::Assemble { input, output }) => assemble(&input, output),
+        Some(cli::Command::Disassemble { input }) => disassemble(&input),
+        None => run(cli.run),
+    }
+}
+
+// Loads an ISO or raw binary into the code region and returns its size in bytes.
+fn load_image(cpu: &mut cpu::MicroCVMCpu, path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    match cli::ImageKind::of(path) {
+        cli::ImageKind::Iso => cpu.read_iso(path),
+        cli::ImageKind::Raw => Ok(cpu.read_memory_from_file(path)?),
+    }
+}
+
+fn assemble(input: &Path, output: Option<PathBuf>) {
+    let output = output.unwrap_or_else(|| input.with_extension("bin"));
+
+    let source = match std::fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error reading {}: {}", input.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let binary = match asm::assemble(&source) {
+        Ok(binary) => binary,
+        Err(e) => {
+            eprintln!("error assembling {}: {}", input.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = std::fs::write(&output, binary) {
+        eprintln!("error writing {}: {}", output.display(), e);
+        std::process::exit(1);
+    }
+}
+
+fn disassemble(input: &Path) {
+    let mut cpu = cpu::MicroCVMCpu::new(cpu::MIN_MEMORY_SIZE, 0, 0);
+
+    let length = match load_image(&mut cpu, input) {
+        Ok(length) => length,
+        Err(e) => {
+            eprintln!("error reading {}: {}", input.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    print!("{}", disasm::disassemble(&cpu, length.div_ceil(2)));
+}
+
+fn run(cli: cli::RunArgs) {
+    let mut machine = match &cli.load_state {
+        Some(path) => snapshot::load(path).unwrap_or_else(|e| {
+            eprintln!("error reading {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        None => {
+            let (width, height) = cli.resolution;
+            let mut machine = cpu::MicroCVMCpu::new(cli.memory, width, height);
+
+            if let Err(e) = load_image(&mut machine, &cli.image) {
+                eprintln!("error reading {}: {}", cli.image.display(), e);
+                std::process::exit(1);
+            }
+
+            machine
+        }
+    };
+
+    // A snapshot brings its own resolution.
+    let framebuffer_width = machine.framebuffer_width;
+    let framebuffer_height = machine.framebuffer_height;
+
+    machine.watchpoints = cli.watch.clone();
+    machine.gamepad_backend = gamepad_backend(&cli);
+    attach_input_recording(&mut machine, &cli);
+    let vcpu = Arc::new(Mutex::new(machine));
+
+    let mut tracer = cli.trace.as_ref().map(|path| {
+        trace::Tracer::create(path, cli.trace_range.clone()).unwrap_or_else(|e| {
+            eprintln!("error creating {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    });
+
+    if cli.debug && let Some(output) = &cli.headless {
+        debug(&vcpu, tracer);
+        vcpu.lock().unwrap().finish_input_recording();
+        save_state(&vcpu.lock().unwrap(), cli.save_state.as_deref());
+
+        if let Err(e) = headless::dump_framebuffer(&vcpu.lock().unwrap(), output) {
+            eprintln!("error writing framebuffer: {}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    if let Some(output) = &cli.headless {
+        if let Some(address) = &cli.gdb
+            && attach_gdb(&vcpu, address, tracer.as_mut()) == gdb::SessionEnd::Killed
+        {
+            finish_trace(tracer.as_mut());
+            return;
+        }
+
+        let mut vcpu = vcpu.lock().unwrap();
+        let summary = headless::run(
+            &mut vcpu,
+            cli.max_instructions,
+            tracer.as_mut(),
+            cli.watch_pause,
+        );
+        finish_trace(tracer.as_mut());
+        vcpu.finish_input_recording();
+        save_state(&vcpu, cli.save_state.as_deref());
+
+        match summary.reason {
+            headless::ExitReason::Halted | headless::ExitReason::ReplayFinished => {}
+            headless::ExitReason::InstructionLimit => eprintln!(
+                "instruction limit reached after {} instructions",
+                summary.instructions
+            ),
+            headless::ExitReason::Fault(e) => eprintln!("guest fault: {}", e),
+            headless::ExitReason::Watchpoint(_) => eprintln!(
+                "stopped on a watchpoint after {} instructions",
+                summary.instructions
+            ),
         }
+
+        if let Err(e) = headless::dump_framebuffer(&vcpu, output) {
+            eprintln!("error writing framebuffer: {}", e);
+            std::process::exit(1);
+        }
+
+        if let headless::ExitReason::Fault(_) = summary.reason {
+            std::process::exit(1);
+        }
+
+        return;
     }
 
+    let max_instructions = cli.max_instructions;
+    let frequency = cli.clock;
+    let watch_pause = cli.watch_pause;
     let vcpu_for_cpu_thread = Arc::clone(&vcpu);
-    thread::spawn(move || {
+    let debug_mode = cli.debug;
+    let gdb_address = cli.gdb.clone();
+    let save_path = cli.save_state.clone();
+    let cpu_thread = thread::spawn(move || {
+        if debug_mode {
+            debug(&vcpu_for_cpu_thread, tracer);
+            vcpu_for_cpu_thread.lock().unwrap().finish_input_recording();
+            save_state(&vcpu_for_cpu_thread.lock().unwrap(), save_path.as_deref());
+            std::process::exit(0);
+        }
+
+        if let Some(address) = &gdb_address
+            && attach_gdb(&vcpu_for_cpu_thread, address, tracer.as_mut())
+                == gdb::SessionEnd::Killed
+        {
+            finish_trace(tracer.as_mut());
+            std::process::exit(0);
+        }
+
+        let mut instructions = 0;
+        let mut clock = frequency
+            .map(|frequency| clock::Clock::new(frequency, vcpu_for_cpu_thread.lock().unwrap().cycles));
+
         loop {
-            let mut vcpu = vcpu_for_cpu_thread.lock().unwrap();
-            let current_pc = vcpu.pc;
-            let opcode_word = vcpu.memory[vcpu.pc as usize];
+            // The lock is released before throttling so the window can draw.
+            let cycles = {
+                let mut vcpu = vcpu_for_cpu_thread.lock().unwrap();
 
-            if opcode_word == cpu::OpcodeType::Hlt as u16 {
-                break;
-            }
+                if vcpu.is_halted() || max_instructions.is_some_and(|limit| instructions >= limit) {
+                    break;
+                }
 
-            let opcode_length = vcpu.execute_instruction();
+                if let Err(e) = trace::step(&mut vcpu, tracer.as_mut()) {
+                    eprintln!("guest fault: {}", e);
+                    break;
+                }
 
-            if vcpu.pc == current_pc {
-                vcpu.pc += opcode_length;
+                instructions += 1;
+
+                if watch::report_hits(&mut vcpu, watch_pause).is_some() {
+                    break;
+                }
+
+                vcpu.cycles
+            };
+
+            if let Some(clock) = &mut clock {
+                clock.throttle(cycles);
             }
         }
 
+        finish_trace(tracer.as_mut());
+        save_state(&vcpu_for_cpu_thread.lock().unwrap(), save_path.as_deref());
+
         println!("Memory execution finished.");
     });
 
-    let (framebuffer_width, framebuffer_height) = {
-        let vcpu_locked = vcpu.lock().unwrap();
-        (
-            vcpu_locked.framebuffer_width,
-            vcpu_locked.framebuffer_height,
-        )
-    };
-
     let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut app = render::App::new(framebuffer_width as u32, framebuffer_height as u32, vcpu);
+    let mut app = render::App::new(
+        framebuffer_width as u32,
+        framebuffer_height as u32,
+        cli.scale,
+        vcpu,
+        cpu_thread,
+        cli.save_state
+            .unwrap_or_else(|| PathBuf::from(render::DEFAULT_STATE_FILE)),
+    );
     let _ = event_loop.run_app(&mut app);
+}
 
+fn gamepad_backend(cli: &cli::RunArgs) -> Option<Box<dyn gamepad::Backend>> {
+    if let Some(path) = &cli.gamepad_script {
+        let script = gamepad::ScriptedBackend::load(path).unwrap_or_else(|e| {
+            eprintln!("error reading {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        return Some(Box::new(script));
+    }
+
+    match cli.gamepad? {
+        cli::GamepadBackend::Keyboard => Some(Box::new(gamepad::KeyboardBackend::default())),
+    }
+}
+
+fn attach_input_recording(machine: &mut cpu::MicroCVMCpu, cli: &cli::RunArgs) {
+    if let Some(path) = &cli.record_input {
+        let recorder = input::Recorder::create(path).unwrap_or_else(|e| {
+            eprintln!("error creating {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        machine.input_recorder = Some(recorder);
+    }
+
+    if let Some(path) = &cli.replay_input {
+        let replay = input::Replay::load(path).unwrap_or_else(|e| {
+            eprintln!("error reading {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        machine.input_replay = Some(replay);
+    }
+}
+
+fn debug(vcpu: &Mutex<cpu::MicroCVMCpu>, tracer: Option<trace::Tracer>) {
+    let stdin = io::stdin();
+    if let Err(e) = debugger::Debugger::new(tracer).run(vcpu, stdin.lock(), io::stdout()) {
+        eprintln!("debugger error: {}", e);
+    }
+}
+
+// A detached guest keeps running; a killed one stops the VM.
+fn attach_gdb(
+    vcpu: &Mutex<cpu::MicroCVMCpu>,
+    address: &str,
+    tracer: Option<&mut trace::Tracer>,
+) -> gdb::SessionEnd {
+    match gdb::serve(vcpu, address, tracer) {
+        Ok(end) => end,
+        Err(e) => {
+            eprintln!("gdb stub error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn finish_trace(tracer: Option<&mut trace::Tracer>) {
+    if let Some(tracer) = tracer
+        && let Err(e) = tracer.finish()
+    {
+        eprintln!("error writing trace: {}", e);
+    }
+}
+
+fn save_state(vcpu: &cpu::MicroCVMCpu, path: Option<&Path>) {
+    if let Some(path) = path
+        && let Err(e) = snapshot::save(vcpu, path)
+    {
+        eprintln!("error writing {}: {}", path.display(), e);
+    }
 }
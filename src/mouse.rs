@@ -0,0 +1,111 @@
+pub const X: u16 = 0x68;
+pub const Y: u16 = 0x69;
+pub const BUTTONS: u16 = 0x6A;
+pub const WHEEL: u16 = 0x6B;
+pub const STATUS: u16 = 0x6C;
+pub const CONTROL: u16 = 0x6D;
+
+pub const BUTTON_LEFT: u16 = 0x0001;
+pub const BUTTON_RIGHT: u16 = 0x0002;
+pub const BUTTON_MIDDLE: u16 = 0x0004;
+pub const BUTTON_BACK: u16 = 0x0008;
+pub const BUTTON_FORWARD: u16 = 0x0010;
+
+pub const STATUS_MOVED: u16 = 0x0001;
+pub const STATUS_BUTTONS: u16 = 0x0002;
+pub const STATUS_WHEEL: u16 = 0x0004;
+pub const STATUS_INSIDE: u16 = 0x0008;
+const STATUS_CHANGES: u16 = STATUS_MOVED | STATUS_BUTTONS | STATUS_WHEEL;
+
+pub const CONTROL_INTERRUPT: u16 = 0x0001;
+
+/// The pointer, in framebuffer pixels.
+///
+/// The position stays at the last pixel the cursor was over when it leaves
+/// the framebuffer. `STATUS` has a bit for each kind of change since it was
+/// last read, and the wheel counts the lines scrolled since `WHEEL` was last
+/// read, positive away from the user.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Mouse {
+    pub x: u16,
+    pub y: u16,
+    pub buttons: u16,
+    pub wheel: i16,
+    // The STATUS_MOVED, STATUS_BUTTONS and STATUS_WHEEL bits not yet read.
+    pub changes: u16,
+    pub inside: bool,
+    pub interrupt: bool,
+}
+
+impl Mouse {
+    /// Records a cursor position from the host. Returns whether it changed.
+    pub fn move_to(&mut self, x: u16, y: u16, inside: bool) -> bool {
+        if (x, y, inside) == (self.x, self.y, self.inside) {
+            return false;
+        }
+
+        self.x = x;
+        self.y = y;
+        self.inside = inside;
+        self.changes |= STATUS_MOVED;
+        true
+    }
+
+    /// Records a press or release of the buttons in `button`. Returns whether
+    /// any of them changed.
+    pub fn button(&mut self, button: u16, pressed: bool) -> bool {
+        let buttons = if pressed {
+            self.buttons | button
+        } else {
+            self.buttons & !button
+        };
+
+        if buttons == self.buttons {
+            return false;
+        }
+
+        self.buttons = buttons;
+        self.changes |= STATUS_BUTTONS;
+        true
+    }
+
+    /// Records a scroll of `lines` wheel lines. Returns whether it was queued.
+    pub fn scroll(&mut self, lines: i16) -> bool {
+        if lines == 0 {
+            return false;
+        }
+
+        self.wheel = self.wheel.saturating_add(lines);
+        self.changes |= STATUS_WHEEL;
+        true
+    }
+
+    pub fn read(&mut self, port: u16) -> Option<u16> {
+        let value = match port {
+            X => self.x,
+            Y => self.y,
+            BUTTONS => self.buttons,
+            WHEEL => std::mem::take(&mut self.wheel) as u16,
+            STATUS => {
+                let mut status = std::mem::take(&mut self.changes) & STATUS_CHANGES;
+                if self.inside {
+                    status |= STATUS_INSIDE;
+                }
+                status
+            }
+            CONTROL => self.interrupt as u16 * CONTROL_INTERRUPT,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    pub fn write(&mut self, port: u16, value: u16) -> Option<()> {
+        match port {
+            CONTROL => self.interrupt = value & CONTROL_INTERRUPT != 0,
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
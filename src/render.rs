@@ -1,13 +1,24 @@
 use pixels::{Pixels, SurfaceTexture};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use winit::application::ApplicationHandler;
-use winit::dpi::{LogicalPosition, LogicalSize};
-use winit::event::WindowEvent;
+use winit::dpi::{LogicalPosition, LogicalSize, PhysicalPosition};
+use winit::event::{Ime, MouseButton, MouseScrollDelta, WindowEvent};
 use winit::event_loop::ActiveEventLoop;
+use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::{Window, WindowAttributes, WindowId};
 
 use crate::cpu;
 use crate::keycodes::physical_key_to_keycode;
+use crate::mouse;
+use crate::snapshot;
+
+// Where the F5 and F9 hotkeys save and load snapshots without --save-state.
+pub const DEFAULT_STATE_FILE: &str = "microcvm.state";
+
+// Touchpads scroll in pixels rather than wheel lines.
+const PIXELS_PER_WHEEL_LINE: f64 = 20.0;
 
 #[derive(Default)]
 pub struct App {
@@ -15,22 +26,40 @@ pub struct App {
     pixels: Option<Pixels<'static>>,
     width: u32,
     height: u32,
+    scale: u32,
     cpu: Arc<Mutex<cpu::MicroCVMCpu>>,
+    // Runs the guest; it exits once the guest halts or faults.
+    cpu_thread: Option<JoinHandle<()>>,
+    state_path: PathBuf,
+    // Whether the window lets an input method compose text, following the
+    // guest's request through the text input device.
+    ime_allowed: bool,
+    // Between Ime::Enabled and Ime::Disabled typed text arrives as
+    // Ime::Commit, so key events must not deliver it a second time.
+    ime_active: bool,
+    // Scrolling not yet delivered as a whole wheel line, in lines. Touchpads
+    // and some mice report fractions of a line.
+    wheel_lines: f64,
 }
 
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window_attributes = WindowAttributes::default()
-            .with_inner_size(LogicalSize::new(self.width, self.height))
+            .with_inner_size(LogicalSize::new(
+                self.width * self.scale,
+                self.height * self.scale,
+            ))
             .with_position(LogicalPosition::new(0, 0))
             .with_title("Virtual Machine Window");
 
         let window = Arc::new(event_loop.create_window(window_attributes).unwrap());
         self.window = Some(window.clone());
 
-        let surface_texture = SurfaceTexture::new(self.width / 2, self.height / 2, window.clone());
-        let pixels = Pixels::new(self.width / 2, self.height / 2, surface_texture).unwrap();
+        let window_size = window.inner_size();
+        let surface_texture =
+            SurfaceTexture::new(window_size.width, window_size.height, window.clone());
+        let pixels = Pixels::new(self.width, self.height, surface_texture).unwrap();
 
         self.pixels = Some(pixels);
     }
@@ -43,22 +72,58 @@ impl ApplicationHandler for App {
     ) {
         match event {
             WindowEvent::CloseRequested => {
+                self.cpu.lock().unwrap().finish_input_recording();
                 event_loop.exit();
             }
             WindowEvent::RedrawRequested => {
+                self.update_ime();
                 self.render();
                 self.window.as_ref().unwrap().request_redraw();
             }
+            // The hotkeys act on the first press; their repeats and releases
+            // are swallowed too, so the guest never sees them.
+            WindowEvent::KeyboardInput { event, .. }
+                if matches!(event.physical_key, PhysicalKey::Code(KeyCode::F5 | KeyCode::F9)) =>
+            {
+                if !event.state.is_pressed() || event.repeat {
+                    return;
+                }
+
+                match event.physical_key {
+                    PhysicalKey::Code(KeyCode::F5) => self.save_state(),
+                    _ => self.load_state(),
+                }
+            }
             WindowEvent::KeyboardInput { event, .. } => {
-                let physical_key = event.physical_key;
+                let keycode = physical_key_to_keycode(&event.physical_key);
                 let mut cpu = self.cpu.lock().unwrap();
-                if event.state.is_pressed() {
-                    cpu.registers[cpu::Register::index(cpu::Register::K0) as usize] =
-                        physical_key_to_keycode(&physical_key);
-                } else {
-                    cpu.registers[cpu::Register::index(cpu::Register::K0) as usize] = 0;
+                cpu.key_event(keycode, event.state.is_pressed(), event.repeat);
+
+                if event.state.is_pressed()
+                    && !self.ime_active
+                    && let Some(text) = &event.text
+                {
+                    cpu.text_input(text);
+                }
+            }
+            WindowEvent::CursorMoved { position, .. } => self.cursor_moved(position),
+            WindowEvent::CursorLeft { .. } => {
+                let mut cpu = self.cpu.lock().unwrap();
+                let mouse = &cpu.devices.mouse;
+                let (x, y) = (mouse.x, mouse.y);
+                cpu.mouse_move(x, y, false);
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                if let Some(button) = mouse_button(button) {
+                    self.cpu.lock().unwrap().mouse_button(button, state.is_pressed());
                 }
             }
+            WindowEvent::MouseWheel { delta, .. } => self.mouse_wheel(delta),
+            WindowEvent::Ime(Ime::Enabled) => self.ime_active = true,
+            WindowEvent::Ime(Ime::Disabled) => self.ime_active = false,
+            WindowEvent::Ime(Ime::Commit(text)) => {
+                self.cpu.lock().unwrap().text_input(&text);
+            }
             _ => (),
         }
     }
@@ -71,7 +136,7 @@ impl App {
         if let Some(pixels) = self.pixels.as_mut() {
             let frame = pixels.frame_mut();
 
-            if cpu.video_memory.len() < frame.len() {
+            if cpu.video_memory.len() < frame.len() / 4 {
                 eprintln!(
                     "Error: Video memory size does not match framebuffer size. Frame size: {}, Video memory size: {}",
                     frame.len(),
@@ -82,7 +147,7 @@ impl App {
 
             let mut byte_index = 0;
             for color in &cpu.video_memory {
-                if !(byte_index + 3 < frame.len()) {
+                if byte_index + 3 >= frame.len() {
                     break;
                 }
                 frame[byte_index] = color.r;
@@ -96,13 +161,130 @@ impl App {
         }
     }
 
-    pub fn new(width: u32, height: u32, cpu: Arc<Mutex<cpu::MicroCVMCpu>>) -> Self {
+    /// Maps a window position to the framebuffer pixel under it, taking the
+    /// scale and any border around the framebuffer into account.
+    fn cursor_moved(&mut self, position: PhysicalPosition<f64>) {
+        let Some(pixels) = self.pixels.as_ref() else {
+            return;
+        };
+
+        let (pixel, inside) = match pixels.window_pos_to_pixel((position.x as f32, position.y as f32)) {
+            Ok(pixel) => (pixel, true),
+            Err(pixel) => (pixels.clamp_pixel_pos(pixel), false),
+        };
+
+        self.cpu
+            .lock()
+            .unwrap()
+            .mouse_move(pixel.0 as u16, pixel.1 as u16, inside);
+    }
+
+    fn mouse_wheel(&mut self, delta: MouseScrollDelta) {
+        self.wheel_lines += match delta {
+            MouseScrollDelta::LineDelta(_, lines) => lines as f64,
+            MouseScrollDelta::PixelDelta(position) => position.y / PIXELS_PER_WHEEL_LINE,
+        };
+
+        let lines = self.wheel_lines.trunc();
+        self.wheel_lines -= lines;
+
+        if lines != 0.0 {
+            self.cpu.lock().unwrap().mouse_scroll(lines as i16);
+        }
+    }
+
+    fn update_ime(&mut self) {
+        let requested = self.cpu.lock().unwrap().devices.text.ime;
+
+        if requested != self.ime_allowed {
+            self.ime_allowed = requested;
+            self.window.as_ref().unwrap().set_ime_allowed(requested);
+        }
+    }
+
+    fn save_state(&self) {
+        let cpu = self.cpu.lock().unwrap();
+
+        match snapshot::save(&cpu, &self.state_path) {
+            Ok(()) => println!("Saved state to {}", self.state_path.display()),
+            Err(e) => eprintln!("error writing {}: {}", self.state_path.display(), e),
+        }
+    }
+
+    // The window keeps its size, so a snapshot taken at another resolution is
+    // rejected.
+    fn load_state(&self) {
+        if self.cpu_thread.as_ref().is_some_and(|thread| thread.is_finished()) {
+            eprintln!(
+                "error reading {}: the guest has stopped and would not run the loaded state",
+                self.state_path.display()
+            );
+            return;
+        }
+
+        let state = match snapshot::load(&self.state_path) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("error reading {}: {}", self.state_path.display(), e);
+                return;
+            }
+        };
+
+        if (state.framebuffer_width, state.framebuffer_height)
+            != (self.width as usize, self.height as usize)
+        {
+            eprintln!(
+                "error reading {}: snapshot resolution {}x{} does not match the window",
+                self.state_path.display(),
+                state.framebuffer_width,
+                state.framebuffer_height
+            );
+            return;
+        }
+
+        let mut cpu = self.cpu.lock().unwrap();
+
+        // The recording could not be replayed across the jump in time.
+        if cpu.input_recorder.is_some() {
+            cpu.finish_input_recording();
+            println!("Input recording ended before loading a snapshot");
+        }
+
+        snapshot::restore(&mut cpu, state);
+        println!("Loaded state from {}", self.state_path.display());
+    }
+
+    pub fn new(
+        width: u32,
+        height: u32,
+        scale: u32,
+        cpu: Arc<Mutex<cpu::MicroCVMCpu>>,
+        cpu_thread: JoinHandle<()>,
+        state_path: PathBuf,
+    ) -> Self {
         Self {
             window: None,
             pixels: None,
             width,
             height,
+            scale,
             cpu,
+            cpu_thread: Some(cpu_thread),
+            state_path,
+            ime_allowed: false,
+            ime_active: false,
+            wheel_lines: 0.0,
         }
     }
 }
+
+fn mouse_button(button: MouseButton) -> Option<u16> {
+    match button {
+        MouseButton::Left => Some(mouse::BUTTON_LEFT),
+        MouseButton::Right => Some(mouse::BUTTON_RIGHT),
+        MouseButton::Middle => Some(mouse::BUTTON_MIDDLE),
+        MouseButton::Back => Some(mouse::BUTTON_BACK),
+        MouseButton::Forward => Some(mouse::BUTTON_FORWARD),
+        MouseButton::Other(_) => None,
+    }
+}
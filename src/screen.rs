@@ -4,18 +4,19 @@ pub struct DrawCommand();
 impl DrawCommand {
     pub fn fill_screen(cpu: &mut super::cpu::MicroCVMCpu, color: super::types::Color) {
         for i in 0..cpu.video_memory.len() {
-            cpu.video_memory[i].r = color.r;
-            cpu.video_memory[i].g = color.g;
-            cpu.video_memory[i].b = color.b;
+            let alpha = cpu.video_memory[i].a;
+            cpu.write_pixel(i, super::types::Color { a: alpha, ..color });
         }
     }
 
     pub fn clear_screen(cpu: &mut super::cpu::MicroCVMCpu) {
-        cpu.video_memory.fill(super::types::Color::new(0, 0, 0));
+        for i in 0..cpu.video_memory.len() {
+            cpu.write_pixel(i, super::types::Color::new(0, 0, 0));
+        }
     }
 
     pub fn get_index_from_coordinate(coordinate: super::types::Point, width: isize) -> isize {
-        coordinate.y * (width as isize) + coordinate.x
+        coordinate.y * width + coordinate.x
     }
 
     pub fn draw_line(
@@ -25,8 +26,8 @@ impl DrawCommand {
         line_end: super::types::Point,
         thickness: isize,
     ) {
-        let width = cpu.framebuffer_width / 2;
-        let height = cpu.framebuffer_height / 2;
+        let width = cpu.framebuffer_width;
+        let height = cpu.framebuffer_height;
 
         let mut x0 = line_start.x;
         let mut y0 = line_start.y;
@@ -55,7 +56,7 @@ impl DrawCommand {
                                 super::types::Point::new(tx, ty),
                                 width as isize,
                             );
-                            cpu.video_memory[index as usize] = color;
+                            cpu.write_pixel(index as usize, color);
                         }
                     }
                 }
@@ -77,28 +78,37 @@ impl DrawCommand {
         }
     }
 
+    /// Draws a bottom-up 24-bit BMP with its top left corner at `position`.
+    /// Returns `None`, without drawing anything, if the file is not one or is
+    /// cut short.
     pub fn draw_bmp(
         cpu: &mut super::cpu::MicroCVMCpu,
         bmp_data: &[u8],
         position: super::types::Point,
-    ) {
+    ) -> Option<()> {
         use super::types::{Color, Point};
 
-        let data_offset =
-            u32::from_le_bytes([bmp_data[10], bmp_data[11], bmp_data[12], bmp_data[13]]) as usize;
-        let width =
-            u32::from_le_bytes([bmp_data[18], bmp_data[19], bmp_data[20], bmp_data[21]]) as usize;
-        let height =
-            u32::from_le_bytes([bmp_data[22], bmp_data[23], bmp_data[24], bmp_data[25]]) as usize;
-        let bits_per_pixel = u16::from_le_bytes([bmp_data[28], bmp_data[29]]);
+        let read_u32 = |offset: usize| -> Option<usize> {
+            let bytes = bmp_data.get(offset..offset + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+        };
+
+        let data_offset = read_u32(10)?;
+        let width = read_u32(18)?;
+        let height = read_u32(22)?;
+        let bits_per_pixel = u16::from_le_bytes(bmp_data.get(28..30)?.try_into().ok()?);
 
         if bits_per_pixel != 24 {
-            panic!("Only 24-bit BMP files are supported.");
+            return None;
         }
 
-        let pitch = ((width * 3 + 3) / 4) * 4;
-        let screen_width = cpu.framebuffer_width / 2;
-        let screen_height = cpu.framebuffer_height / 2;
+        let pitch = width.checked_mul(3)?.div_ceil(4) * 4;
+        let end = pitch.checked_mul(height)?.checked_add(data_offset)?;
+        if end > bmp_data.len() {
+            return None;
+        }
+        let screen_width = cpu.framebuffer_width;
+        let screen_height = cpu.framebuffer_height;
 
         for y in 0..height {
             for x in 0..width {
@@ -120,10 +130,12 @@ impl DrawCommand {
                         Point::new(screen_x, screen_y),
                         screen_width as isize,
                     );
-                    cpu.video_memory[screen_index as usize] = Color::new(r, g, b);
+                    cpu.write_pixel(screen_index as usize, Color::new(r, g, b));
                 }
             }
         }
+
+        Some(())
     }
 
     pub fn fill_rect(
@@ -132,8 +144,8 @@ impl DrawCommand {
         center: super::types::Point,
         size: isize,
     ) {
-        let width = cpu.framebuffer_width / 2;
-        let height = cpu.framebuffer_height / 2;
+        let width = cpu.framebuffer_width;
+        let height = cpu.framebuffer_height;
 
         let top_left_x = center.x - size / 2;
         let top_left_y = center.y - size / 2;
@@ -145,7 +157,7 @@ impl DrawCommand {
                         super::types::Point::new(x, y),
                         width as isize,
                     );
-                    cpu.video_memory[index as usize] = color;
+                    cpu.write_pixel(index as usize, color);
                 }
             }
         }
@@ -179,12 +191,15 @@ impl DrawCommand {
             for x in min_x..=max_x {
                 let point = super::types::Point::new(x, y);
 
-                if Self::point_in_triangle(point, p1, p2, p3) {
+                if x >= 0
+                    && x < cpu.framebuffer_width as isize
+                    && y >= 0
+                    && y < cpu.framebuffer_height as isize
+                    && Self::point_in_triangle(point, p1, p2, p3)
+                {
                     let index =
                         Self::get_index_from_coordinate(point, cpu.framebuffer_width as isize);
-                    if index >= 0 && index < cpu.video_memory.len() as isize {
-                        cpu.video_memory[index as usize] = color;
-                    }
+                    cpu.write_pixel(index as usize, color);
                 }
             }
         }
@@ -226,8 +241,8 @@ impl DrawCommand {
                 let screen_x = character_position.x + x as isize + bounds.min.x as isize;
                 let screen_y = character_position.y + y as isize + bounds.min.y as isize;
 
-                let screen_width = cpu.framebuffer_width / 2;
-                let screen_height = cpu.framebuffer_height / 2;
+                let screen_width = cpu.framebuffer_width;
+                let screen_height = cpu.framebuffer_height;
 
                 if screen_x >= 0
                     && screen_y >= 0
@@ -239,10 +254,13 @@ impl DrawCommand {
                         screen_width as isize,
                     );
 
-                    cpu.video_memory[index as usize] = super::types::Color::new(
-                        (color.r as f32 * v) as u8,
-                        (color.g as f32 * v) as u8,
-                        (color.b as f32 * v) as u8,
+                    cpu.write_pixel(
+                        index as usize,
+                        super::types::Color::new(
+                            (color.r as f32 * v) as u8,
+                            (color.g as f32 * v) as u8,
+                            (color.b as f32 * v) as u8,
+                        ),
                     );
                 }
             });
@@ -0,0 +1,416 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::cpu::{self, MicroCVMCpu, Register};
+use crate::types::Color;
+
+const MAGIC: &[u8; 8] = b"MCVMSNAP";
+const VERSION: u16 = 1;
+
+/// Writes the whole machine state to `path`.
+///
+/// All values are little-endian:
+///
+/// ```text
+/// magic "MCVMSNAP", version: u16
+/// pc: u16, sp: u16, flags: u16, cycles: u64
+/// register count: u16, registers: [u16]
+/// framebuffer width: u32, framebuffer height: u32
+/// memory length in words: u32, memory: [u16]
+/// video memory length in pixels: u32, video memory: [r, g, b, a]
+/// timer: enabled: u8, period: u32, deadline: u64, ticks: u32, expired: u8,
+///     ticks latch: u16
+/// interrupts: pending: u16, mask: u16, frame: u64
+/// keyboard: repeat: u8, overflow: u8, queue length: u16, queue: [u16],
+///     held length: u16, held: [u16]
+/// text input: ime: u8, overflow: u8, queue length: u16, queue: [u16]
+/// mouse: x: u16, y: u16, buttons: u16, wheel: i16, changes: u16, inside: u8,
+///     interrupt: u8
+/// gamepad: connected: u8, buttons: u16, axes: [i16; 4], changed: u8,
+///     interrupt: u8
+/// ```
+///
+/// Watchpoints belong to the debugging session, not the machine, and are not
+/// saved.
+pub fn save(cpu: &MicroCVMCpu, path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writer.write_all(MAGIC)?;
+    write_u16(&mut writer, VERSION)?;
+
+    write_u16(&mut writer, cpu.pc)?;
+    write_u16(&mut writer, cpu.sp)?;
+    write_u16(&mut writer, cpu.flags)?;
+    write_u64(&mut writer, cpu.cycles)?;
+
+    write_u16(&mut writer, cpu.registers.len() as u16)?;
+    for &register in &cpu.registers {
+        write_u16(&mut writer, register)?;
+    }
+
+    write_u32(&mut writer, cpu.framebuffer_width as u32)?;
+    write_u32(&mut writer, cpu.framebuffer_height as u32)?;
+
+    write_u32(&mut writer, cpu.memory.len() as u32)?;
+    for &word in &cpu.memory {
+        write_u16(&mut writer, word)?;
+    }
+
+    write_u32(&mut writer, cpu.video_memory.len() as u32)?;
+    for color in &cpu.video_memory {
+        writer.write_all(&[color.r, color.g, color.b, color.a])?;
+    }
+
+    let timer = &cpu.devices.timer;
+    write_u8(&mut writer, timer.enabled as u8)?;
+    write_u32(&mut writer, timer.period)?;
+    write_u64(&mut writer, timer.deadline)?;
+    write_u32(&mut writer, timer.ticks)?;
+    write_u8(&mut writer, timer.expired as u8)?;
+    write_u16(&mut writer, timer.ticks_latch)?;
+
+    write_u16(&mut writer, cpu.devices.interrupts.pending)?;
+    write_u16(&mut writer, cpu.devices.interrupts.mask)?;
+    write_u64(&mut writer, cpu.devices.frame)?;
+
+    let keyboard = &cpu.devices.keyboard;
+    write_u8(&mut writer, keyboard.repeat as u8)?;
+    write_u8(&mut writer, keyboard.overflow as u8)?;
+    write_words(&mut writer, keyboard.queue.iter().copied())?;
+    write_words(&mut writer, keyboard.held.iter().copied())?;
+
+    let text = &cpu.devices.text;
+    write_u8(&mut writer, text.ime as u8)?;
+    write_u8(&mut writer, text.overflow as u8)?;
+    write_words(&mut writer, text.queue.iter().copied())?;
+
+    let mouse = &cpu.devices.mouse;
+    write_u16(&mut writer, mouse.x)?;
+    write_u16(&mut writer, mouse.y)?;
+    write_u16(&mut writer, mouse.buttons)?;
+    write_u16(&mut writer, mouse.wheel as u16)?;
+    write_u16(&mut writer, mouse.changes)?;
+    write_u8(&mut writer, mouse.inside as u8)?;
+    write_u8(&mut writer, mouse.interrupt as u8)?;
+
+    let gamepad = &cpu.devices.gamepad;
+    write_u8(&mut writer, gamepad.state.connected as u8)?;
+    write_u16(&mut writer, gamepad.state.buttons)?;
+    for axis in gamepad.state.axes {
+        write_u16(&mut writer, axis as u16)?;
+    }
+    write_u8(&mut writer, gamepad.changed as u8)?;
+    write_u8(&mut writer, gamepad.interrupt as u8)?;
+
+    writer.flush()
+}
+
+/// Reads a snapshot written by `save` into a new machine.
+pub fn load(path: &Path) -> io::Result<MicroCVMCpu> {
+    let file = File::open(path)?;
+    let length = file.metadata()?.len();
+
+    read_snapshot(BufReader::new(file), length).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("snapshot is truncated".to_string()),
+        _ => e,
+    })
+}
+
+// `length` is the size of the whole snapshot, which bounds the memory it can
+// hold, so a damaged header cannot make us allocate more than the file.
+fn read_snapshot(mut reader: impl Read, length: u64) -> io::Result<MicroCVMCpu> {
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a MicroCVM snapshot".to_string()));
+    }
+
+    let version = read_u16(&mut reader)?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "unsupported snapshot version {} (expected {})",
+            version, VERSION
+        )));
+    }
+
+    let pc = read_u16(&mut reader)?;
+    let sp = read_u16(&mut reader)?;
+    let flags = read_u16(&mut reader)?;
+    let cycles = read_u64(&mut reader)?;
+
+    let register_count = read_u16(&mut reader)? as usize;
+    if register_count != Register::ALL.len() {
+        return Err(invalid(format!(
+            "snapshot has {} registers, expected {}",
+            register_count,
+            Register::ALL.len()
+        )));
+    }
+
+    let mut registers = [0; Register::ALL.len()];
+    for register in &mut registers {
+        *register = read_u16(&mut reader)?;
+    }
+
+    let framebuffer_width = read_u32(&mut reader)? as usize;
+    let framebuffer_height = read_u32(&mut reader)? as usize;
+
+    let memory_words = read_u32(&mut reader)? as usize;
+    if memory_words as u64 * 2 > length {
+        return Err(invalid(format!(
+            "snapshot memory is {} bytes, but the file is only {} bytes",
+            memory_words as u64 * 2,
+            length
+        )));
+    }
+
+    if memory_words * 2 < cpu::MIN_MEMORY_SIZE {
+        return Err(invalid(format!(
+            "snapshot memory is {} bytes, expected at least {}",
+            memory_words * 2,
+            cpu::MIN_MEMORY_SIZE
+        )));
+    }
+
+    let mut machine = MicroCVMCpu::new(memory_words * 2, framebuffer_width, framebuffer_height);
+    for word in &mut machine.memory {
+        *word = read_u16(&mut reader)?;
+    }
+
+    let video_pixels = read_u32(&mut reader)? as usize;
+    if video_pixels != cpu::VIDEO_MEMORY
+        || framebuffer_width
+            .checked_mul(framebuffer_height)
+            .is_none_or(|pixels| pixels > video_pixels)
+    {
+        return Err(invalid(format!(
+            "snapshot video memory is {} pixels, expected {}",
+            video_pixels,
+            cpu::VIDEO_MEMORY
+        )));
+    }
+
+    for color in &mut machine.video_memory {
+        let mut rgba = [0; 4];
+        reader.read_exact(&mut rgba)?;
+        *color = Color {
+            r: rgba[0],
+            g: rgba[1],
+            b: rgba[2],
+            a: rgba[3],
+        };
+    }
+
+    let timer = &mut machine.devices.timer;
+    timer.enabled = read_u8(&mut reader)? != 0;
+    timer.period = read_u32(&mut reader)?;
+    timer.deadline = read_u64(&mut reader)?;
+    timer.ticks = read_u32(&mut reader)?;
+    timer.expired = read_u8(&mut reader)? != 0;
+    timer.ticks_latch = read_u16(&mut reader)?;
+
+    machine.devices.interrupts.pending = read_u16(&mut reader)?;
+    machine.devices.interrupts.mask = read_u16(&mut reader)?;
+    machine.devices.frame = read_u64(&mut reader)?;
+
+    let keyboard = &mut machine.devices.keyboard;
+    keyboard.repeat = read_u8(&mut reader)? != 0;
+    keyboard.overflow = read_u8(&mut reader)? != 0;
+    keyboard.queue = read_words(&mut reader)?.into();
+    keyboard.held = read_words(&mut reader)?;
+
+    let text = &mut machine.devices.text;
+    text.ime = read_u8(&mut reader)? != 0;
+    text.overflow = read_u8(&mut reader)? != 0;
+    text.queue = read_words(&mut reader)?.into();
+
+    let mouse = &mut machine.devices.mouse;
+    mouse.x = read_u16(&mut reader)?;
+    mouse.y = read_u16(&mut reader)?;
+    mouse.buttons = read_u16(&mut reader)?;
+    mouse.wheel = read_u16(&mut reader)? as i16;
+    mouse.changes = read_u16(&mut reader)?;
+    mouse.inside = read_u8(&mut reader)? != 0;
+    mouse.interrupt = read_u8(&mut reader)? != 0;
+
+    let gamepad = &mut machine.devices.gamepad;
+    gamepad.state.connected = read_u8(&mut reader)? != 0;
+    gamepad.state.buttons = read_u16(&mut reader)?;
+    for axis in &mut gamepad.state.axes {
+        *axis = read_u16(&mut reader)? as i16;
+    }
+    gamepad.changed = read_u8(&mut reader)? != 0;
+    gamepad.interrupt = read_u8(&mut reader)? != 0;
+
+    machine.pc = pc;
+    machine.sp = sp;
+    machine.flags = flags;
+    machine.cycles = cycles;
+    machine.registers = registers;
+
+    Ok(machine)
+}
+
+/// Replaces the state of `cpu` with `snapshot`, keeping its watchpoints,
+/// gamepad backend and input recording or replay, which belong to the host
+/// rather than the machine. A replay goes back to the snapshot's cycle count.
+pub fn restore(cpu: &mut MicroCVMCpu, snapshot: MicroCVMCpu) {
+    let watchpoints = std::mem::take(&mut cpu.watchpoints);
+    let gamepad_backend = cpu.gamepad_backend.take();
+    let input_recorder = cpu.input_recorder.take();
+    let input_replay = cpu.input_replay.take();
+    *cpu = snapshot;
+    cpu.watchpoints = watchpoints;
+    cpu.gamepad_backend = gamepad_backend;
+    cpu.input_recorder = input_recorder;
+    cpu.input_replay = input_replay;
+
+    if let Some(replay) = &mut cpu.input_replay {
+        replay.rewind(cpu.cycles);
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u8(writer: &mut impl Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+fn write_u16(writer: &mut impl Write, value: u16) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+// A u16 count followed by the words.
+fn write_words(writer: &mut impl Write, words: impl ExactSizeIterator<Item = u16>) -> io::Result<()> {
+    write_u16(writer, words.len() as u16)?;
+    for word in words {
+        write_u16(writer, word)?;
+    }
+
+    Ok(())
+}
+
+fn read_words(reader: &mut impl Read) -> io::Result<Vec<u16>> {
+    let count = read_u16(reader)?;
+    (0..count).map(|_| read_u16(reader)).collect()
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamepad::GamepadState;
+
+    // Fills the screen, then writes a run of words into the data area.
+    const PROGRAM: &str = "mov v0, 40\nmov v1, 80\nmov v2, 120\nsys fill_screen\n\
+                           mov r0, 0x200\nmov r1, 7\nloop: store r0, r1\nadd r0, 2\nadd r1, 3\n\
+                           cmp r0, 0x228\njne loop\nhlt\n";
+
+    fn snapshot_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("microcvm-{}-{}.snap", name, std::process::id()))
+    }
+
+    fn assert_same_machine(a: &MicroCVMCpu, b: &MicroCVMCpu) {
+        assert_eq!((a.pc, a.sp, a.flags, a.cycles), (b.pc, b.sp, b.flags, b.cycles));
+        assert_eq!(a.registers, b.registers);
+        assert_eq!(
+            (a.framebuffer_width, a.framebuffer_height),
+            (b.framebuffer_width, b.framebuffer_height)
+        );
+        assert!(a.memory == b.memory);
+        assert!(a.video_memory == b.video_memory);
+        assert_eq!(a.devices, b.devices);
+    }
+
+    #[test]
+    fn round_trip_resumes_identically() {
+        let mut cpu = MicroCVMCpu::from_source(PROGRAM);
+        for _ in 0..20 {
+            cpu.step().unwrap();
+        }
+
+        cpu.devices.key_event(0x41, true, false);
+        cpu.devices.text_input("h\u{e9}\u{1F600}");
+        cpu.devices.mouse_move(5, 6, true);
+        cpu.devices.mouse_button(crate::mouse::BUTTON_LEFT, true);
+        cpu.devices.mouse_scroll(-2);
+        cpu.devices.gamepad_update(GamepadState {
+            connected: true,
+            buttons: crate::gamepad::BUTTON_START,
+            axes: [1, -2, 3, -32767],
+        });
+
+        let path = snapshot_path("round-trip");
+        save(&cpu, &path).unwrap();
+        let mut loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_same_machine(&cpu, &loaded);
+
+        while !cpu.is_halted() {
+            cpu.step().unwrap();
+            loaded.step().unwrap();
+        }
+        assert!(loaded.is_halted());
+        assert_same_machine(&cpu, &loaded);
+    }
+
+    #[test]
+    fn rejects_damaged_snapshots() {
+        let path = snapshot_path("damaged");
+        save(&MicroCVMCpu::from_source(PROGRAM), &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let read = |bytes: &[u8]| read_snapshot(bytes, bytes.len() as u64).map(|_| ());
+        assert!(read(&bytes).is_ok());
+
+        let mut other_version = bytes.clone();
+        other_version[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(read(&other_version).is_err());
+
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read(b"not a snapshot").is_err());
+
+        // A memory length larger than the file is rejected before allocating.
+        let memory_length = MAGIC.len() + 2 + 14 + 2 + 2 * Register::ALL.len() + 8;
+        let mut huge = bytes.clone();
+        huge[memory_length..memory_length + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = read(&huge).unwrap_err().to_string();
+        assert!(error.starts_with("snapshot memory is"), "{}", error);
+    }
+}
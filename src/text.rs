@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+pub const DATA: u16 = 0x64;
+pub const STATUS: u16 = 0x65;
+pub const CONTROL: u16 = 0x66;
+
+pub const STATUS_AVAILABLE: u16 = 0x0001;
+pub const STATUS_OVERFLOW: u16 = 0x0002;
+
+pub const CONTROL_IME: u16 = 0x0001;
+pub const CONTROL_FLUSH: u16 = 0x0002;
+
+const QUEUE_CAPACITY: usize = 64;
+
+/// Typed text, after the host has applied the keyboard layout, shift, dead
+/// keys and any input method.
+///
+/// The queue holds UTF-16 code units: ASCII and most other characters take
+/// one word, anything outside the Basic Multilingual Plane a surrogate pair.
+/// Reading `DATA` takes the oldest one, or 0 when the queue is empty.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TextInput {
+    pub queue: VecDeque<u16>,
+    // Set when text was dropped because the queue was full; cleared by
+    // reading `STATUS`.
+    pub overflow: bool,
+    // Whether the guest asked for input method composition.
+    pub ime: bool,
+}
+
+impl TextInput {
+    /// Queues `text` from the host. A character that does not fit is dropped
+    /// whole, along with the rest of the text. Returns whether anything was
+    /// queued.
+    pub fn push(&mut self, text: &str) -> bool {
+        let mut queued = false;
+
+        for character in text.chars() {
+            let mut units = [0; 2];
+            let units = character.encode_utf16(&mut units);
+
+            if self.queue.len() + units.len() > QUEUE_CAPACITY {
+                self.overflow = true;
+                break;
+            }
+
+            self.queue.extend(units.iter());
+            queued = true;
+        }
+
+        queued
+    }
+
+    pub fn read(&mut self, port: u16) -> Option<u16> {
+        let value = match port {
+            DATA => self.queue.pop_front().unwrap_or(0),
+            STATUS => {
+                let mut status = 0;
+                if !self.queue.is_empty() {
+                    status |= STATUS_AVAILABLE;
+                }
+                if std::mem::take(&mut self.overflow) {
+                    status |= STATUS_OVERFLOW;
+                }
+                status
+            }
+            CONTROL => self.ime as u16 * CONTROL_IME,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    pub fn write(&mut self, port: u16, value: u16) -> Option<()> {
+        match port {
+            CONTROL => {
+                self.ime = value & CONTROL_IME != 0;
+                if value & CONTROL_FLUSH != 0 {
+                    self.queue.clear();
+                }
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
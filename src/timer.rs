@@ -0,0 +1,81 @@
+pub const CONTROL: u16 = 0x40;
+pub const PERIOD_LOW: u16 = 0x41;
+pub const PERIOD_HIGH: u16 = 0x42;
+pub const STATUS: u16 = 0x43;
+pub const TICKS_LOW: u16 = 0x44;
+pub const TICKS_HIGH: u16 = 0x45;
+
+pub const CONTROL_ENABLE: u16 = 0x0001;
+pub const STATUS_EXPIRED: u16 = 0x0001;
+
+/// A programmable interval timer counting CPU cycles, so it runs at the same
+/// guest speed with or without `--clock`.
+///
+/// Every `period` cycles while enabled it adds one to `ticks`, sets the
+/// expired bit in `STATUS`, which stays set until the guest writes `STATUS`,
+/// and raises the timer interrupt.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Timer {
+    pub enabled: bool,
+    pub period: u32,
+    // The cycle count at which the current period ends.
+    pub deadline: u64,
+    pub ticks: u32,
+    pub expired: bool,
+    // The high half of `ticks`, latched when the low half is read.
+    pub ticks_latch: u16,
+}
+
+impl Timer {
+    pub fn read(&mut self, port: u16) -> Option<u16> {
+        let value = match port {
+            CONTROL => self.enabled as u16 * CONTROL_ENABLE,
+            PERIOD_LOW => self.period as u16,
+            PERIOD_HIGH => (self.period >> 16) as u16,
+            STATUS => self.expired as u16 * STATUS_EXPIRED,
+            TICKS_LOW => {
+                self.ticks_latch = (self.ticks >> 16) as u16;
+                self.ticks as u16
+            }
+            TICKS_HIGH => self.ticks_latch,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /// Enabling the timer starts a new period at `cycles`. A new period length
+    /// applies from the next one.
+    pub fn write(&mut self, port: u16, value: u16, cycles: u64) -> Option<()> {
+        match port {
+            CONTROL => {
+                self.enabled = value & CONTROL_ENABLE != 0;
+                self.deadline = cycles + self.period as u64;
+            }
+            PERIOD_LOW => self.period = (self.period & 0xFFFF_0000) | value as u32,
+            PERIOD_HIGH => self.period = (self.period & 0x0000_FFFF) | (value as u32) << 16,
+            STATUS => self.expired = false,
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    /// Whether a period has ended by `cycles`.
+    pub fn due(&self, cycles: u64) -> bool {
+        self.enabled && self.period != 0 && cycles >= self.deadline
+    }
+
+    /// Returns whether a period ended, which raises the timer interrupt.
+    pub fn tick(&mut self, cycles: u64) -> bool {
+        if !self.due(cycles) {
+            return false;
+        }
+
+        let periods = (cycles - self.deadline) / self.period as u64 + 1;
+        self.ticks = self.ticks.wrapping_add(periods as u32);
+        self.deadline += periods * self.period as u64;
+        self.expired = true;
+        true
+    }
+}
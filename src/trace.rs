@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::cpu::{Executed, MicroCVMCpu, Register, VmError};
+use crate::disasm;
+
+/// Writes one line per executed instruction: the pc, the raw words, the
+/// disassembly and every register, `sp` or flag that the instruction changed.
+/// Entering a hardware interrupt handler gets a line of its own.
+///
+/// ```text
+/// 001A  0007 1002         inc r1                   r1=0051
+/// 001C  0017 2004 1002    load8 v3, r1             v3=0065
+/// 002D                    interrupt 0x08           sp=3FFD flags=-----
+/// ```
+pub struct Tracer {
+    writer: BufWriter<File>,
+    range: Option<RangeInclusive<u16>>,
+    error: Option<io::Error>,
+}
+
+struct State {
+    registers: [u16; Register::ALL.len()],
+    sp: u16,
+    flags: u16,
+}
+
+impl State {
+    fn capture(cpu: &MicroCVMCpu) -> Self {
+        Self {
+            registers: cpu.registers,
+            sp: cpu.sp,
+            flags: cpu.flags,
+        }
+    }
+}
+
+impl Tracer {
+    /// Only instructions whose pc lies in `range` (code offsets) are traced.
+    pub fn create(path: &Path, range: Option<RangeInclusive<u16>>) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            range,
+            error: None,
+        })
+    }
+
+    /// Executes one instruction, tracing it if its pc is in range.
+    pub fn step(&mut self, cpu: &mut MicroCVMCpu) -> Result<(), VmError> {
+        let pc = cpu.pc;
+        if self.range.as_ref().is_some_and(|range| !range.contains(&pc)) {
+            return cpu.step();
+        }
+
+        let instruction = disasm::decode(cpu, pc, usize::MAX);
+        let before = State::capture(cpu);
+        let result = cpu.execute_instruction();
+
+        let mut line = match result {
+            Ok(Executed::Interrupt(vector)) => {
+                format!("{:04X}  {:<14}    {:<24}", pc, "", format!("interrupt {:#04X}", vector))
+            }
+            _ => {
+                let words = instruction
+                    .words
+                    .iter()
+                    .map(|word| format!("{:04X}", word))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!(
+                    "{:04X}  {:<14}    {:<24}",
+                    pc,
+                    words,
+                    disasm::format_instruction(&instruction, &HashMap::new())
+                )
+            }
+        };
+
+        match &result {
+            Ok(_) => line.push_str(&deltas(&before, cpu)),
+            Err(e) => line.push_str(&format!(" ! {}", e)),
+        }
+
+        self.write_line(line.trim_end());
+        result.map(|_| ())
+    }
+
+    /// Flushes the trace and reports the first write error, if any.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        self.writer.flush()
+    }
+
+    // A failed write stops the trace but not the guest; the error is kept
+    // for `finish`.
+    fn write_line(&mut self, line: &str) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            self.error = Some(e);
+        }
+    }
+}
+
+fn deltas(before: &State, cpu: &MicroCVMCpu) -> String {
+    let mut deltas = String::new();
+
+    for register in Register::ALL {
+        let index = register.index();
+        if before.registers[index] != cpu.registers[index] {
+            deltas.push_str(&format!(" {}={:04X}", register, cpu.registers[index]));
+        }
+    }
+
+    if before.sp != cpu.sp {
+        deltas.push_str(&format!(" sp={:04X}", cpu.sp));
+    }
+
+    if before.flags != cpu.flags {
+        deltas.push_str(&format!(" flags={}", cpu.flag_names()));
+    }
+
+    deltas
+}
+
+/// Executes one instruction through `tracer` when tracing is enabled.
+pub fn step(cpu: &mut MicroCVMCpu, tracer: Option<&mut Tracer>) -> Result<(), VmError> {
+    match tracer {
+        Some(tracer) => tracer.step(cpu),
+        None => cpu.step(),
+    }
+}
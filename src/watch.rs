@@ -0,0 +1,216 @@
+use std::fmt::Display;
+
+use crate::cpu::MicroCVMCpu;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    pub fn matches(self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    // Physical byte addresses, `end` exclusive.
+    Memory { start: usize, end: usize },
+    // A rectangle of framebuffer pixels; only writes are reported.
+    Video {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub target: WatchTarget,
+}
+
+impl Watchpoint {
+    pub fn memory(kind: WatchKind, start: usize, length: usize) -> Self {
+        Self {
+            kind,
+            target: WatchTarget::Memory {
+                start,
+                end: start + length.max(1),
+            },
+        }
+    }
+
+    pub fn video(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            kind: WatchKind::Write,
+            target: WatchTarget::Video {
+                x,
+                y,
+                width,
+                height,
+            },
+        }
+    }
+
+    /// Whether an access to `length` bytes at physical byte `address` overlaps
+    /// this watchpoint.
+    pub fn matches_memory(&self, address: usize, length: usize, access: Access) -> bool {
+        match self.target {
+            WatchTarget::Memory { start, end } => {
+                self.kind.matches(access) && address < end && start < address + length
+            }
+            WatchTarget::Video { .. } => false,
+        }
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::ReadWrite => "read/write",
+        };
+
+        match self.target {
+            WatchTarget::Memory { start, end } if end - start == 1 => {
+                write!(f, "{} {:#X}", kind, start)
+            }
+            WatchTarget::Memory { start, end } => write!(f, "{} {:#X}-{:#X}", kind, start, end - 1),
+            WatchTarget::Video {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "video write ({}, {}) {}x{}", x, y, width, height),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitLocation {
+    // The physical byte address and size of the access.
+    Memory { address: usize, length: usize },
+    // The first pixel in the watched rectangle that changed.
+    Pixel { x: usize, y: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    // The pc of the instruction that made the access.
+    pub pc: u16,
+    pub access: Access,
+    pub watchpoint: Watchpoint,
+    pub location: HitLocation,
+}
+
+impl Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+
+        match self.location {
+            HitLocation::Memory { address, length } => write!(
+                f,
+                "{} of {} byte(s) at {:#X} (pc = {:#06X})",
+                access, length, address, self.pc
+            ),
+            HitLocation::Pixel { x, y } => write!(
+                f,
+                "video write at ({}, {}) (pc = {:#06X})",
+                x, y, self.pc
+            ),
+        }
+    }
+}
+
+/// Parses a watchpoint from the command line:
+///
+/// - `[r:|w:|rw:]START[-END]` watches physical byte addresses (writes by
+///   default), e.g. `w:0x21000-0x2100F`
+/// - `video:X,Y,WxH` watches writes to a rectangle of the framebuffer
+pub fn parse_watchpoint(value: &str) -> Result<Watchpoint, String> {
+    if let Some(rectangle) = value.strip_prefix("video:") {
+        let invalid = || format!("invalid video region `{}`, expected X,Y,WxH", rectangle);
+        let mut parts = rectangle.split(',');
+        let (Some(x), Some(y), Some(size), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+
+        let number = |text: &str| parse_address(text).ok_or_else(invalid);
+        return Ok(Watchpoint::video(
+            number(x)?,
+            number(y)?,
+            number(width)?,
+            number(height)?,
+        ));
+    }
+
+    let (kind, range) = match value.split_once(':') {
+        Some(("r", range)) => (WatchKind::Read, range),
+        Some(("w", range)) => (WatchKind::Write, range),
+        Some(("rw", range)) => (WatchKind::ReadWrite, range),
+        Some((kind, _)) => return Err(format!("unknown watch kind `{}`, expected r, w or rw", kind)),
+        None => (WatchKind::Write, value),
+    };
+
+    let invalid = |text: &str| format!("invalid address `{}`", text);
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (
+            parse_address(start).ok_or_else(|| invalid(start))?,
+            parse_address(end).ok_or_else(|| invalid(end))?,
+        ),
+        None => {
+            let address = parse_address(range).ok_or_else(|| invalid(range))?;
+            (address, address)
+        }
+    };
+
+    if start > end {
+        return Err(format!("range start {:#X} is after its end {:#X}", start, end));
+    }
+
+    Ok(Watchpoint::memory(kind, start, end - start + 1))
+}
+
+// Physical addresses go past 16 bits, so this does not reuse the assembler's
+// number parser.
+fn parse_address(text: &str) -> Option<usize> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Logs and clears the hits of the last instruction. Returns the first hit
+/// when execution should pause on it.
+pub fn report_hits(cpu: &mut MicroCVMCpu, pause: bool) -> Option<WatchHit> {
+    let first = cpu.watch_hits.first().copied();
+
+    for hit in cpu.watch_hits.drain(..) {
+        eprintln!("watchpoint: {}", hit);
+    }
+
+    first.filter(|_| pause)
+}
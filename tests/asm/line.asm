@@ -5,4 +5,5 @@ mov v4, 73 ; Set starting x to 73
 mov v5, 300 ; Set starting y to 300
 mov v6, 230 ; Set ending x to 230
 mov v7, 67 ; Set ending y to 67
-call draw_line
+sys draw_line
+hlt